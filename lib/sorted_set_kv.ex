@@ -3,33 +3,161 @@ defmodule SortedSetKV do
     otp_app: :sorted_set_kv,
     crate: :sortedsetkv
 
-  def open(_path), do: :erlang.nif_error(:nif_not_loaded)
+  def open(_path, _opts \\ []), do: :erlang.nif_error(:nif_not_loaded)
   def clear(_db), do: :erlang.nif_error(:nif_not_loaded)
+  def flush(_db), do: :erlang.nif_error(:nif_not_loaded)
 
-  def zadd(_db, _collection, _key, _value, _score, _add_if_gt),
+  def flush_async(db) do
+    ref = make_ref()
+    with :ok <- flush_async(db, ref), do: {:ok, ref}
+  end
+
+  def flush_async(_db, _ref), do: :erlang.nif_error(:nif_not_loaded)
+  def close(_db), do: :erlang.nif_error(:nif_not_loaded)
+
+  def zadd(_db, _collection, _key, _value, _score, _add_if_gt_or_opts),
     do: :erlang.nif_error(:nif_not_loaded)
 
+  def zmadd(_db, _collection, _entries, _opts \\ []), do: :erlang.nif_error(:nif_not_loaded)
+
   def zexists(_db, _collection, _min_score, _max_score),
     do: :erlang.nif_error(:nif_not_loaded)
 
-  def zscoreupdate(_db, _collection, _key, _score, _min_score),
+  def zscoretype(_db, _collection), do: :erlang.nif_error(:nif_not_loaded)
+
+  def zsetscoretype(_db, _collection, _score_type), do: :erlang.nif_error(:nif_not_loaded)
+
+  def zcard(_db, _collection), do: :erlang.nif_error(:nif_not_loaded)
+
+  def zcount(_db, _collection, _min_score, _max_score),
     do: :erlang.nif_error(:nif_not_loaded)
 
-  def zrangebyscore(_db, _collection, _min_score, _max_score, _offset, _limit),
+  def zscoreupdate(_db, _collection, _key, _score, _update_if_gt_or_opts),
     do: :erlang.nif_error(:nif_not_loaded)
 
-  def zrangebyprefixscore(_db, _collection, _prefix, _min_score, _max_score, _offset, _limit),
+  def zrangebyscore(_db, _collection, _min_score, _max_score, _offset, _limit, _opts \\ []),
     do: :erlang.nif_error(:nif_not_loaded)
 
+  def zrangebyprefixscore(
+        _db,
+        _collection,
+        _prefix,
+        _min_score,
+        _max_score,
+        _offset,
+        _limit,
+        _opts \\ []
+      ),
+      do: :erlang.nif_error(:nif_not_loaded)
+
+  def zrevrangebyscore(_db, _collection, _min_score, _max_score, _offset, _limit, _opts \\ []),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  def zrevrangebyprefixscore(
+        _db,
+        _collection,
+        _prefix,
+        _min_score,
+        _max_score,
+        _offset,
+        _limit,
+        _opts \\ []
+      ),
+      do: :erlang.nif_error(:nif_not_loaded)
+
+  def zscanbyscore(_db, _collection, _min_score, _max_score, _cursor, _limit, _opts \\ []),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  def zrevscanbyscore(_db, _collection, _min_score, _max_score, _cursor, _limit, _opts \\ []),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  def zscanbyprefixscore(
+        _db,
+        _collection,
+        _prefix,
+        _min_score,
+        _max_score,
+        _cursor,
+        _limit,
+        _opts \\ []
+      ),
+      do: :erlang.nif_error(:nif_not_loaded)
+
+  def zrevscanbyprefixscore(
+        _db,
+        _collection,
+        _prefix,
+        _min_score,
+        _max_score,
+        _cursor,
+        _limit,
+        _opts \\ []
+      ),
+      do: :erlang.nif_error(:nif_not_loaded)
+
   def zscore(_db, _collection, _key), do: :erlang.nif_error(:nif_not_loaded)
+  def zincrby(_db, _collection, _key, _delta), do: :erlang.nif_error(:nif_not_loaded)
 
   def zrembyrangebyscore(_db, _collection, _min_score, _max_score, _limit),
     do: :erlang.nif_error(:nif_not_loaded)
 
   def zgetbykey(_db, _collection, _key, _min_score), do: :erlang.nif_error(:nif_not_loaded)
+  def zmget(_db, _collection, _keys, _min_score), do: :erlang.nif_error(:nif_not_loaded)
+  def zmscore(_db, _collection, _keys), do: :erlang.nif_error(:nif_not_loaded)
   def zrem(_db, _collection, _key), do: :erlang.nif_error(:nif_not_loaded)
+  def zpopkey(_db, _collection, _key), do: :erlang.nif_error(:nif_not_loaded)
+
+  def zpopmin(_db, _collection, _count, _max_score \\ nil),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  def zpopmax(_db, _collection, _count, _max_score \\ nil),
+    do: :erlang.nif_error(:nif_not_loaded)
+
   def lpush(_db, _collection, _value), do: :erlang.nif_error(:nif_not_loaded)
   def rpush(_db, _collection, _value), do: :erlang.nif_error(:nif_not_loaded)
   def lpop(_db, _collection), do: :erlang.nif_error(:nif_not_loaded)
   def rpop(_db, _collection), do: :erlang.nif_error(:nif_not_loaded)
+  def llen(_db, _collection), do: :erlang.nif_error(:nif_not_loaded)
+  def lrange(_db, _collection, _start, _stop), do: :erlang.nif_error(:nif_not_loaded)
+  def lindex(_db, _collection, _index), do: :erlang.nif_error(:nif_not_loaded)
+  def lpeek(_db, _collection), do: :erlang.nif_error(:nif_not_loaded)
+  def rpeek(_db, _collection), do: :erlang.nif_error(:nif_not_loaded)
+  def ltrim(_db, _collection, _start, _stop), do: :erlang.nif_error(:nif_not_loaded)
+  def lrem(_db, _collection, _value, _count), do: :erlang.nif_error(:nif_not_loaded)
+  def pipeline(_db, _ops, _opts \\ []), do: :erlang.nif_error(:nif_not_loaded)
+  def transaction(_db, _ops), do: :erlang.nif_error(:nif_not_loaded)
+
+  def blpop(db, collection, timeout) do
+    ref = make_ref()
+    with :ok <- blpop(db, collection, timeout, ref), do: {:ok, ref}
+  end
+
+  def blpop(_db, _collection, _timeout, _ref), do: :erlang.nif_error(:nif_not_loaded)
+
+  def brpop(db, collection, timeout) do
+    ref = make_ref()
+    with :ok <- brpop(db, collection, timeout, ref), do: {:ok, ref}
+  end
+
+  def brpop(_db, _collection, _timeout, _ref), do: :erlang.nif_error(:nif_not_loaded)
+
+  def bzpopmin(db, collection, timeout, max_score \\ nil) do
+    ref = make_ref()
+    with :ok <- bzpopmin(db, collection, timeout, max_score, ref), do: {:ok, ref}
+  end
+
+  def bzpopmin(_db, _collection, _timeout, _max_score, _ref),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  def bcancel(db, ref) do
+    with :ok <- cancel_blocking(db, ref) do
+      receive do
+        {:sortedsetkv, ^ref, result} -> result
+      after
+        0 -> :cancelled
+      end
+    end
+  end
+
+  def cancel_blocking(_db, _ref), do: :erlang.nif_error(:nif_not_loaded)
 end
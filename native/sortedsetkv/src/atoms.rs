@@ -1,5 +1,56 @@
 rustler::atoms! {
     ok,
     error,
-    sled_error
+    io,
+    corruption,
+    collection_not_found,
+    unsupported,
+    reportable_bug,
+    closed,
+    system_limit,
+    out_of_memory,
+    overflow,
+    too_many_waiters,
+    not_empty,
+    busy,
+    sortedsetkv,
+    cache_capacity,
+    flush_every_ms,
+    mode,
+    low_space,
+    high_throughput,
+    use_compression,
+    compression_factor,
+    temporary,
+    create_new,
+    segment_size,
+    nx,
+    xx,
+    gt,
+    lt,
+    ch,
+    return_previous,
+    withscores,
+    withvalues,
+    incl,
+    excl,
+    inf,
+    neg_inf = "-inf",
+    timeout,
+    atomic,
+    zadd,
+    zscoreupdate,
+    zincrby,
+    zrem,
+    zscore,
+    zgetbykey,
+    lpush,
+    rpush,
+    lpop,
+    rpop,
+    popped,
+    batch_size,
+    u64,
+    i64,
+    f64
 }
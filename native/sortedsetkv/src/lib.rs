@@ -1,761 +1,3607 @@
 pub mod atoms;
 
 use {
-    log::error, rustler::Encoder, rustler::NifResult,
-    sled::transaction::TransactionError as SledTransactionError, sled::IVec, sled::Transactional,
-    std::convert::TryInto, std::io::Write,
+    log::error,
+    rustler::env::OwnedEnv,
+    rustler::types::map::MapIterator,
+    rustler::Encoder,
+    rustler::NifResult,
+    sled::transaction::TransactionError as SledTransactionError,
+    sled::transaction::{
+        ConflictableTransactionError, ConflictableTransactionResult, TransactionalTree,
+    },
+    sled::IVec,
+    sled::Transactional,
+    std::collections::hash_map::DefaultHasher,
+    std::collections::{BTreeMap, HashMap, HashSet},
+    std::convert::TryInto,
+    std::hash::{Hash, Hasher},
+    std::ops::{Bound, RangeBounds},
+    std::sync::mpsc::RecvTimeoutError,
+    std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    std::time::{Duration, Instant},
 };
 
-const SCORE_PREFIX: &'static [u8; 6] = b"scores";
-const KEY_PREFIX: &'static [u8; 4] = b"keys";
-const LIST_PREFIX: &'static [u8; 5] = b"lists";
-const VALUE_SUFFIX: &'static [u8; 1] = b"v";
-const SCORE_SUFFIX: &'static [u8; 1] = b"s";
+const SCORE_PREFIX: &[u8; 6] = b"scores";
+const KEY_PREFIX: &[u8; 4] = b"keys";
+const LIST_PREFIX: &[u8; 5] = b"lists";
+// Collection metadata lives in the default tree under the prefix and the collection name.
+const META_PREFIX: &[u8; 4] = b"meta";
+const VALUE_SUFFIX: &[u8; 1] = b"v";
+const SCORE_SUFFIX: &[u8; 1] = b"s";
+// Number of members removed per transaction by range removals.
+const REMOVE_CHUNK_SIZE: usize = 256;
+const COLLECTION_LOCK_STRIPES: usize = 64;
+const DEFAULT_TREE: &[u8; 15] = b"__sled__default";
+// Longest a blocking pop sleeps before checking whether the database was closed.
+const BLOCKING_POLL_MS: u64 = 100;
+// Blocking pops each hold an OS thread while they wait, so only this many may wait at once.
+const MAX_BLOCKING_WAITERS: usize = 1024;
+// Key and list trees keep their member count under the empty key, which no entry can use since
+// member entries always end with a suffix and list keys are eight bytes. It is removed when the
+// count drops to zero.
+const CARD_KEY: &[u8; 0] = b"";
+
+// The process and reference a `flush_async` result is sent to.
+type FlushWaiter = (rustler::LocalPid, OwnedEnv, rustler::env::SavedTerm);
 
 pub struct DbResource {
-    pub db: sled::Db,
+    db: RwLock<Option<sled::Db>>,
+    // Callers waiting for the next round of the flush thread, or `None` when it is not running.
+    flushing: Mutex<Option<Vec<FlushWaiter>>>,
+    // Every tree that exists on disk, with its handle once it has been opened.
+    trees: RwLock<HashMap<Vec<u8>, Option<sled::Tree>>>,
+    collection_locks: Vec<RwLock<()>>,
+    // Trees with blocking pops waiting on them, which must not be dropped while watched.
+    watched: Mutex<HashMap<Vec<u8>, usize>>,
+    // Cancel flags of waiting blocking pops, by their encoded reference.
+    waiters: Mutex<HashMap<Vec<u8>, Arc<Mutex<bool>>>>,
+    // Score types of the collections looked up so far.
+    score_types: RwLock<HashMap<Vec<u8>, ScoreType>>,
 }
 
-fn io_err_into(e: SledTransactionError<SledTransactionError>) -> rustler::error::Error {
-    error!("Sled Error: {}", e.to_string());
-    rustler::error::Error::Term(Box::new(atoms::sled_error()))
+impl DbResource {
+    fn new(db: sled::Db) -> Self {
+        let trees = db
+            .tree_names()
+            .into_iter()
+            .filter(|name| name != DEFAULT_TREE)
+            .map(|name| (name.to_vec(), None))
+            .collect();
+        DbResource {
+            db: RwLock::new(Some(db)),
+            flushing: Mutex::new(None),
+            trees: RwLock::new(trees),
+            collection_locks: (0..COLLECTION_LOCK_STRIPES)
+                .map(|_| RwLock::new(()))
+                .collect(),
+            watched: Mutex::new(HashMap::new()),
+            waiters: Mutex::new(HashMap::new()),
+            score_types: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn db(&self) -> NifResult<sled::Db> {
+        self.db
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+            .ok_or_else(closed_err)
+    }
+
+    fn close(&self) -> NifResult<sled::Db> {
+        let mut trees = self.trees.write().unwrap_or_else(|e| e.into_inner());
+        let db = self
+            .db
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+            .ok_or_else(closed_err)?;
+        trees.clear();
+        Ok(db)
+    }
+
+    fn tree(&self, prefix: &[u8], collection: &[u8]) -> NifResult<sled::Tree> {
+        let name = tree_name(prefix, collection);
+        if let Some(Some(tree)) = self
+            .trees
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&name)
+        {
+            return Ok(tree.clone());
+        }
+
+        let mut trees = self.trees.write().unwrap_or_else(|e| e.into_inner());
+        if let Some(Some(tree)) = trees.get(&name) {
+            return Ok(tree.clone());
+        }
+        let tree = self.db()?.open_tree(&name).map_err(sled_err_into)?;
+        trees.insert(name, Some(tree.clone()));
+        Ok(tree)
+    }
+
+    // Like `tree` but returns `None` instead of creating a tree that does not exist yet.
+    fn existing_tree(&self, prefix: &[u8], collection: &[u8]) -> NifResult<Option<sled::Tree>> {
+        // Closing forgets every tree, so a miss would otherwise hide `:closed`.
+        self.db()?;
+        let name = tree_name(prefix, collection);
+        match self
+            .trees
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&name)
+        {
+            Some(Some(tree)) => return Ok(Some(tree.clone())),
+            Some(None) => (),
+            None => return Ok(None),
+        }
+
+        self.tree(prefix, collection).map(Some)
+    }
+
+    fn collection_stripe(&self, collection: &[u8]) -> usize {
+        let mut hasher = DefaultHasher::new();
+        collection.hash(&mut hasher);
+        hasher.finish() as usize % self.collection_locks.len()
+    }
+
+    fn collection_lock(&self, collection: &[u8]) -> &RwLock<()> {
+        &self.collection_locks[self.collection_stripe(collection)]
+    }
+
+    // Like `lock_collection` for several collections, locking exclusively those paired with true.
+    // Stripes are locked once each and in order, so callers locking overlapping sets cannot
+    // deadlock.
+    fn lock_collections<'b>(
+        &self,
+        collections: impl Iterator<Item = (&'b [u8], bool)>,
+    ) -> Vec<CollectionGuard<'_>> {
+        let mut stripes = BTreeMap::new();
+        for (collection, exclusive) in collections {
+            *stripes
+                .entry(self.collection_stripe(collection))
+                .or_insert(false) |= exclusive;
+        }
+        stripes
+            .into_iter()
+            .map(|(stripe, exclusive)| {
+                let lock = &self.collection_locks[stripe];
+                if exclusive {
+                    CollectionGuard::Exclusive(lock.write().unwrap_or_else(|e| e.into_inner()))
+                } else {
+                    CollectionGuard::Shared(lock.read().unwrap_or_else(|e| e.into_inner()))
+                }
+            })
+            .collect()
+    }
+
+    // Held while writing to a collection so its trees cannot be dropped underneath the write.
+    // Locks are striped and may be shared with other collections, so every NIF taking one must
+    // run on a dirty scheduler.
+    fn lock_collection(&self, collection: &[u8]) -> RwLockReadGuard<'_, ()> {
+        self.collection_lock(collection)
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Excludes every other writer of the collection, for changes that read before they write.
+    fn lock_collection_exclusive(&self, collection: &[u8]) -> RwLockWriteGuard<'_, ()> {
+        self.collection_lock(collection)
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn drop_tree_if_empty(&self, prefix: &[u8], collection: &[u8]) -> NifResult<bool> {
+        let _guard = self.lock_collection_exclusive(collection);
+
+        match self.existing_tree(prefix, collection)? {
+            Some(tree) if tree.is_empty() => (),
+            _ => return Ok(false),
+        }
+
+        if self.is_watched(prefix, collection) {
+            return Ok(false);
+        }
+        let name = tree_name(prefix, collection);
+        let mut trees = self.trees.write().unwrap_or_else(|e| e.into_inner());
+        trees.remove(&name);
+        self.db()?.drop_tree(name).map_err(sled_err_into)
+    }
+
+    // Subscribes to changes of a tree, creating it if needed, and keeps it from being dropped
+    // until `unwatch_tree` is called.
+    fn watch_tree(&self, prefix: &[u8], collection: &[u8]) -> NifResult<sled::Subscriber> {
+        let _guard = self.lock_collection(collection);
+        let tree = self.tree(prefix, collection)?;
+        *self
+            .watched
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(tree_name(prefix, collection))
+            .or_insert(0) += 1;
+        Ok(tree.watch_prefix(vec![]))
+    }
+
+    fn is_watched(&self, prefix: &[u8], collection: &[u8]) -> bool {
+        self.watched
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains_key(&tree_name(prefix, collection))
+    }
+
+    fn unwatch_tree(&self, prefix: &[u8], collection: &[u8]) -> NifResult<bool> {
+        {
+            let mut watched = self.watched.lock().unwrap_or_else(|e| e.into_inner());
+            let name = tree_name(prefix, collection);
+            if let Some(count) = watched.get_mut(&name) {
+                *count -= 1;
+                if *count == 0 {
+                    watched.remove(&name);
+                }
+            }
+        }
+        self.drop_tree_if_empty(prefix, collection)
+    }
+
+    // Collections without a score type keep the original u64 scores.
+    fn score_type(&self, collection: &[u8]) -> NifResult<ScoreType> {
+        let db = self.db()?;
+        if let Some(&score_type) = self
+            .score_types
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(collection)
+        {
+            return Ok(score_type);
+        }
+
+        let mut score_types = self.score_types.write().unwrap_or_else(|e| e.into_inner());
+        if let Some(&score_type) = score_types.get(collection) {
+            return Ok(score_type);
+        }
+        let score_type = match db
+            .get(tree_name(META_PREFIX, collection))
+            .map_err(sled_err_into)?
+        {
+            Some(tag) => ScoreType::from_tag(&tag)
+                .ok_or_else(|| corruption_err(format!("invalid score type {:?}", tag)))?,
+            None => ScoreType::U64,
+        };
+        score_types.insert(collection.to_vec(), score_type);
+        Ok(score_type)
+    }
+
+    fn set_score_type(&self, collection: &[u8], score_type: ScoreType) -> NifResult<()> {
+        let mut score_types = self.score_types.write().unwrap_or_else(|e| e.into_inner());
+        self.db()?
+            .insert(tree_name(META_PREFIX, collection), score_type.tag())
+            .map_err(sled_err_into)?;
+        score_types.insert(collection.to_vec(), score_type);
+        Ok(())
+    }
+
+    fn clear(&self) -> NifResult<()> {
+        let mut trees = self.trees.write().unwrap_or_else(|e| e.into_inner());
+        let mut score_types = self.score_types.write().unwrap_or_else(|e| e.into_inner());
+        let db = self.db()?;
+        trees.clear();
+        score_types.clear();
+
+        db.clear().map_err(sled_err_into)?;
+
+        for name in db.tree_names() {
+            if name != DEFAULT_TREE {
+                db.drop_tree(name).map_err(sled_err_into)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Guards are only held, never read.
+#[allow(dead_code)]
+enum CollectionGuard<'a> {
+    Shared(RwLockReadGuard<'a, ()>),
+    Exclusive(RwLockWriteGuard<'a, ()>),
+}
+
+fn tree_name(prefix: &[u8], collection: &[u8]) -> Vec<u8> {
+    prefix.iter().chain(collection).copied().collect()
+}
+
+fn closed_err() -> rustler::error::Error {
+    rustler::error::Error::Term(Box::new(atoms::closed()))
+}
+
+fn sled_err_reason(e: sled::Error) -> (rustler::Atom, String) {
+    error!("Sled Error: {}", e);
+    let kind = match e {
+        sled::Error::CollectionNotFound(_) => atoms::collection_not_found(),
+        sled::Error::Unsupported(_) => atoms::unsupported(),
+        sled::Error::ReportableBug(_) => atoms::reportable_bug(),
+        sled::Error::Io(_) => atoms::io(),
+        sled::Error::Corruption { .. } => atoms::corruption(),
+    };
+    (kind, e.to_string())
+}
+
+fn transaction_err_into(e: SledTransactionError<rustler::error::Error>) -> rustler::error::Error {
+    match e {
+        SledTransactionError::Abort(e) => e,
+        SledTransactionError::Storage(e) => sled_err_into(e),
+    }
+}
+
+fn sled_err_into(e: sled::Error) -> rustler::error::Error {
+    rustler::error::Error::Term(Box::new(sled_err_reason(e)))
+}
+
+fn corruption_err(message: String) -> rustler::error::Error {
+    error!("Corrupt Entry: {}", message);
+    rustler::error::Error::Term(Box::new((atoms::corruption(), message)))
+}
+
+// A failure reading stored entries, turned into an error term once it reaches a NIF. Helpers that
+// only touch sled return it so they can be tested without the BEAM.
+#[derive(Debug)]
+enum StoreError {
+    Sled(sled::Error),
+    Corruption(String),
+}
+
+impl From<sled::Error> for StoreError {
+    fn from(e: sled::Error) -> Self {
+        StoreError::Sled(e)
+    }
+}
+
+impl From<StoreError> for rustler::error::Error {
+    fn from(e: StoreError) -> Self {
+        match e {
+            StoreError::Sled(e) => sled_err_into(e),
+            StoreError::Corruption(message) => corruption_err(message),
+        }
+    }
+}
+
+impl From<StoreError> for ConflictableTransactionError<rustler::error::Error> {
+    fn from(e: StoreError) -> Self {
+        ConflictableTransactionError::Abort(e.into())
+    }
+}
+
+fn decode_options(opts: rustler::Term) -> NifResult<Vec<(rustler::Atom, rustler::Term)>> {
+    match MapIterator::new(opts) {
+        Some(iter) => iter.map(|(k, v)| Ok((k.decode()?, v))).collect(),
+        None => opts.decode(),
+    }
+}
+
+fn make_config(path: &str, opts: rustler::Term) -> NifResult<sled::Config> {
+    let mut config = sled::Config::default().path(path);
+
+    for (k, v) in decode_options(opts)? {
+        config = if k == atoms::cache_capacity() {
+            config.cache_capacity(v.decode()?)
+        } else if k == atoms::flush_every_ms() {
+            config.flush_every_ms(v.decode()?)
+        } else if k == atoms::mode() {
+            let mode: rustler::Atom = v.decode()?;
+            if mode == atoms::low_space() {
+                config.mode(sled::Mode::LowSpace)
+            } else if mode == atoms::high_throughput() {
+                config.mode(sled::Mode::HighThroughput)
+            } else {
+                return Err(rustler::Error::BadArg);
+            }
+        } else if k == atoms::use_compression() {
+            config.use_compression(v.decode()?)
+        } else if k == atoms::compression_factor() {
+            config.compression_factor(v.decode()?)
+        } else if k == atoms::temporary() {
+            config.temporary(v.decode()?)
+        } else if k == atoms::create_new() {
+            config.create_new(v.decode()?)
+        } else if k == atoms::segment_size() {
+            config.segment_size(v.decode()?)
+        } else {
+            return Err(rustler::Error::BadArg);
+        };
+    }
+
+    Ok(config)
+}
+
+#[derive(Default, Clone, Copy)]
+struct WriteOptions {
+    nx: bool,
+    xx: bool,
+    gt: bool,
+    lt: bool,
+    ch: bool,
+    return_previous: bool,
+    legacy_gt: bool,
+}
+
+impl WriteOptions {
+    // Accepts the legacy `gt` boolean or a keyword list/map of options.
+    fn decode(term: rustler::Term) -> NifResult<Self> {
+        if let Ok(gt) = term.decode::<bool>() {
+            return Ok(WriteOptions {
+                gt,
+                legacy_gt: true,
+                ..Default::default()
+            });
+        }
+
+        let mut options = WriteOptions::default();
+        for (k, v) in decode_options(term)? {
+            if !options.decode_option(k, v)? {
+                return Err(rustler::Error::BadArg);
+            }
+        }
+        options.validate()
+    }
+
+    // Returns false for options other than write options.
+    fn decode_option(&mut self, k: rustler::Atom, v: rustler::Term) -> NifResult<bool> {
+        if k == atoms::nx() {
+            self.nx = v.decode()?;
+        } else if k == atoms::xx() {
+            self.xx = v.decode()?;
+        } else if k == atoms::gt() {
+            self.gt = v.decode()?;
+        } else if k == atoms::lt() {
+            self.lt = v.decode()?;
+        } else if k == atoms::ch() {
+            self.ch = v.decode()?;
+        } else if k == atoms::return_previous() {
+            self.return_previous = v.decode()?;
+        } else {
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn validate(self) -> NifResult<Self> {
+        if (self.nx && (self.xx || self.gt || self.lt)) || (self.gt && self.lt) {
+            return Err(rustler::Error::BadArg);
+        }
+        Ok(self)
+    }
+
+    // GT and LT only restrict updates of members that already have a score, and never allow
+    // the score to be removed from them. The legacy `gt` boolean still lets `nil` remove it.
+    fn allows(&self, exists: bool, old_score: Option<u64>, score: Option<u64>) -> bool {
+        if (self.nx && exists) || (self.xx && !exists) {
+            return false;
+        }
+        match (old_score, score) {
+            (Some(old), Some(new)) => (!self.gt || new > old) && (!self.lt || new < old),
+            (Some(_), None) => self.legacy_gt || (!self.gt && !self.lt),
+            (None, _) => true,
+        }
+    }
+}
+
+struct WriteResult {
+    written: bool,
+    changed: bool,
+    previous_value: Option<IVec>,
+    previous_score: Option<IVec>,
+}
+
+impl WriteResult {
+    fn encode<'a>(
+        self,
+        env: rustler::Env<'a>,
+        options: &WriteOptions,
+        score_type: ScoreType,
+    ) -> NifResult<rustler::Term<'a>> {
+        let written = if options.ch {
+            (self.changed as u64).encode(env)
+        } else {
+            self.written.encode(env)
+        };
+        if options.return_previous {
+            let previous = make_entry(env, self.previous_value, self.previous_score, score_type)?;
+            Ok((written, previous).encode(env))
+        } else if options.ch {
+            Ok(written)
+        } else {
+            Ok(atoms::ok().encode(env))
+        }
+    }
+}
+
+// Writes a member's value and score to the key tree and keeps the score index in step.
+// `value` of `None` keeps the current value. Without `create` only members that already have a
+// score are written.
+fn write_member(
+    score_tree: &TransactionalTree,
+    key_tree: &TransactionalTree,
+    key: &[u8],
+    value: Option<Option<&[u8]>>,
+    score: Option<u64>,
+    create: bool,
+    options: &WriteOptions,
+) -> ConflictableTransactionResult<WriteResult, rustler::error::Error> {
+    let value_key_bytes = key
+        .iter()
+        .chain(VALUE_SUFFIX.iter())
+        .copied()
+        .collect::<Vec<_>>();
+    let score_key_bytes = key
+        .iter()
+        .chain(SCORE_SUFFIX.iter())
+        .copied()
+        .collect::<Vec<_>>();
+
+    let old_value = key_tree.get(&value_key_bytes)?;
+    let old_score_bytes = key_tree.get(&score_key_bytes)?;
+    let old_score = old_score_bytes.as_ref().map(|s| make_u64(s)).transpose()?;
+    let exists = old_value.is_some() || old_score_bytes.is_some();
+
+    let written = (create || old_score.is_some()) && options.allows(exists, old_score, score);
+    let new_value = value.unwrap_or(old_value.as_deref());
+    let changed = written
+        && if exists {
+            old_score != score || old_value.as_deref() != new_value
+        } else {
+            new_value.is_some() || score.is_some()
+        };
+
+    if changed {
+        if !exists {
+            adjust_card(key_tree, 1)?;
+        } else if new_value.is_none() && score.is_none() {
+            adjust_card(key_tree, -1)?;
+        }
+        if let Some(old) = &old_score_bytes {
+            score_tree.remove(old.iter().chain(key).copied().collect::<Vec<_>>())?;
+        }
+        if let Some(v) = new_value {
+            key_tree.insert(value_key_bytes, v)?;
+        } else {
+            key_tree.remove(value_key_bytes)?;
+        }
+        if let Some(s) = score {
+            let score_bin = s.to_be_bytes();
+            key_tree.insert(score_key_bytes, &score_bin)?;
+            score_tree.insert(
+                score_bin.iter().chain(key).copied().collect::<Vec<_>>(),
+                b"",
+            )?;
+        } else {
+            key_tree.remove(score_key_bytes)?;
+        }
+    }
+
+    Ok(WriteResult {
+        written,
+        changed,
+        previous_value: old_value,
+        previous_score: old_score_bytes,
+    })
+}
+
+fn adjust_card(
+    tree: &TransactionalTree,
+    delta: i64,
+) -> ConflictableTransactionResult<(), rustler::error::Error> {
+    let card = match tree.get(CARD_KEY)? {
+        Some(card) => make_u64(&card)?,
+        None => 0,
+    };
+    match card.saturating_add_signed(delta) {
+        0 => tree.remove(CARD_KEY)?,
+        card => tree.insert(CARD_KEY, &card.to_be_bytes())?,
+    };
+    Ok(())
+}
+
+// A member has a value entry, a score entry or both.
+fn count_members(key_tree: &sled::Tree) -> sled::Result<u64> {
+    let mut count = 0;
+    for k in key_tree.iter().keys() {
+        let k = k?;
+        if k.last() == SCORE_SUFFIX.last() {
+            count += 1;
+        } else if k.last() == VALUE_SUFFIX.last() {
+            let score_key_bytes = k[..(k.len() - 1)]
+                .iter()
+                .chain(SCORE_SUFFIX.iter())
+                .copied()
+                .collect::<Vec<_>>();
+            if !key_tree.contains_key(score_key_bytes)? {
+                count += 1;
+            }
+        }
+    }
+    Ok(count)
+}
+
+// Collections and lists written before counts were kept get theirs when the database is opened.
+fn backfill_counts(db: &sled::Db) -> sled::Result<()> {
+    for name in db.tree_names() {
+        let is_list = name.starts_with(LIST_PREFIX);
+        if !is_list && !name.starts_with(KEY_PREFIX) {
+            continue;
+        }
+        let tree = db.open_tree(&name)?;
+        if tree.is_empty() || tree.contains_key(CARD_KEY)? {
+            continue;
+        }
+        let count = if is_list {
+            tree.len() as u64
+        } else {
+            count_members(&tree)?
+        };
+        tree.insert(CARD_KEY, &count.to_be_bytes())?;
+    }
+    Ok(())
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn open<'a>(
+    env: rustler::Env<'a>,
+    a: String,
+    opts: rustler::Term<'a>,
+) -> NifResult<rustler::Term<'a>> {
+    let db: sled::Db = make_config(&a, opts)?.open().map_err(sled_err_into)?;
+    backfill_counts(&db).map_err(sled_err_into)?;
+    let db_resouce = rustler::ResourceArc::new(DbResource::new(db));
+    Ok((atoms::ok(), db_resouce).encode(env))
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn clear<'a>(db_resouce: rustler::Term<'a>) -> NifResult<rustler::Atom> {
+    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
+    dbr.clear()?;
+
+    Ok(atoms::ok())
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn flush<'a>(db_resouce: rustler::Term<'a>) -> NifResult<usize> {
+    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
+    let db = &dbr.db()?;
+
+    db.flush().map_err(sled_err_into)
+}
+
+#[rustler::nif]
+fn flush_async<'a>(
+    env: rustler::Env<'a>,
+    db_resouce: rustler::Term<'a>,
+    reference: rustler::Term<'a>,
+) -> NifResult<rustler::Atom> {
+    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
+    let db = dbr.db()?;
+    let msg_env = OwnedEnv::new();
+    let saved_reference = msg_env.save(reference);
+    let waiter = (env.pid(), msg_env, saved_reference);
+
+    // Calls made while a flush runs are answered by one more flush once it is done, so there is
+    // never more than one flush thread.
+    let mut flushing = dbr.flushing.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(waiters) = flushing.as_mut() {
+        waiters.push(waiter);
+        return Ok(atoms::ok());
+    }
+
+    let flusher = dbr.clone();
+    std::thread::Builder::new()
+        .spawn(move || loop {
+            let waiters = {
+                let mut flushing = flusher.flushing.lock().unwrap_or_else(|e| e.into_inner());
+                match flushing.take() {
+                    Some(waiters) if !waiters.is_empty() => {
+                        *flushing = Some(Vec::new());
+                        waiters
+                    }
+                    _ => break,
+                }
+            };
+
+            let result = db.flush().map_err(sled_err_reason);
+            for (pid, mut msg_env, saved_reference) in waiters {
+                msg_env.send_and_clear(&pid, |env| {
+                    let reference = saved_reference.load(env);
+                    match &result {
+                        Ok(flushed) => {
+                            (atoms::sortedsetkv(), reference, (atoms::ok(), flushed)).encode(env)
+                        }
+                        Err(reason) => {
+                            (atoms::sortedsetkv(), reference, (atoms::error(), reason)).encode(env)
+                        }
+                    }
+                });
+            }
+        })
+        .map_err(|_| rustler::error::Error::Term(Box::new(atoms::system_limit())))?;
+    *flushing = Some(vec![waiter]);
+
+    Ok(atoms::ok())
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn close<'a>(db_resouce: rustler::Term<'a>) -> NifResult<rustler::Atom> {
+    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
+    let db = dbr.close()?;
+
+    db.flush().map_err(sled_err_into)?;
+
+    Ok(atoms::ok())
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn zadd<'a>(
+    env: rustler::Env<'a>,
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    key: rustler::Binary,
+    value: Option<rustler::Binary>,
+    score: Option<rustler::Term<'a>>,
+    options: rustler::Term<'a>,
+) -> NifResult<rustler::Term<'a>> {
+    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
+    let options = WriteOptions::decode(options)?;
+    let _guard = dbr.lock_collection(&collection);
+    let score_type = dbr.score_type(&collection)?;
+    let score = score.map(|s| score_type.decode(s)).transpose()?;
+
+    let score_tree = dbr.tree(SCORE_PREFIX, &collection)?;
+    let key_tree = dbr.tree(KEY_PREFIX, &collection)?;
+    let value = value.as_ref().map(|v| v.as_slice());
+
+    (&score_tree, &key_tree)
+        .transaction(|(score_tree, key_tree)| {
+            write_member(
+                score_tree,
+                key_tree,
+                &key,
+                Some(value),
+                score,
+                true,
+                &options,
+            )
+        })
+        .map_err(transaction_err_into)?
+        .encode(env, &options, score_type)
+}
+
+// Adds many members of one collection, applying the write options to each entry. Entries are
+// written in one transaction, or in transactions of `batch_size` entries. Returns how many
+// members were added, how many existing members changed and how many entries were skipped by
+// the options or left their member unchanged.
+#[rustler::nif(schedule = "DirtyIo")]
+fn zmadd<'a>(
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    entries: Vec<(
+        rustler::Binary,
+        Option<rustler::Binary>,
+        Option<rustler::Term<'a>>,
+    )>,
+    opts: rustler::Term<'a>,
+) -> NifResult<(u64, u64, u64)> {
+    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
+
+    let mut options = WriteOptions::default();
+    let mut batch_size = entries.len().max(1);
+    for (k, v) in decode_options(opts)? {
+        if k == atoms::batch_size() {
+            batch_size = v.decode()?;
+            if batch_size == 0 {
+                return Err(rustler::Error::BadArg);
+            }
+        } else if k == atoms::ch()
+            || k == atoms::return_previous()
+            || !options.decode_option(k, v)?
+        {
+            // The result is counts, so `ch` and `return_previous` do not apply.
+            return Err(rustler::Error::BadArg);
+        }
+    }
+    let options = options.validate()?;
+
+    let _guard = dbr.lock_collection(&collection);
+    let score_type = dbr.score_type(&collection)?;
+    let entries = entries
+        .into_iter()
+        .map(|(key, value, score)| {
+            let score = score.map(|s| score_type.decode(s)).transpose()?;
+            Ok((key, value, score))
+        })
+        .collect::<NifResult<Vec<_>>>()?;
+    let score_tree = dbr.tree(SCORE_PREFIX, &collection)?;
+    let key_tree = dbr.tree(KEY_PREFIX, &collection)?;
+
+    let (mut added, mut updated, mut skipped) = (0, 0, 0);
+    for batch in entries.chunks(batch_size) {
+        let (batch_added, batch_updated, batch_skipped) = (&score_tree, &key_tree)
+            .transaction(|(score_tree, key_tree)| {
+                let (mut added, mut updated, mut skipped) = (0, 0, 0);
+                for (key, value, score) in batch {
+                    let value = value.as_ref().map(|v| v.as_slice());
+                    let result = write_member(
+                        score_tree,
+                        key_tree,
+                        key,
+                        Some(value),
+                        *score,
+                        true,
+                        &options,
+                    )?;
+                    if !result.changed {
+                        skipped += 1;
+                    } else if result.previous_value.is_none() && result.previous_score.is_none() {
+                        added += 1;
+                    } else {
+                        updated += 1;
+                    }
+                }
+                Ok((added, updated, skipped))
+            })
+            .map_err(transaction_err_into)?;
+        added += batch_added;
+        updated += batch_updated;
+        skipped += batch_skipped;
+    }
+    Ok((added, updated, skipped))
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn zscoreupdate<'a>(
+    env: rustler::Env<'a>,
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    key: rustler::Binary,
+    score: Option<rustler::Term<'a>>,
+    options: rustler::Term<'a>,
+) -> NifResult<rustler::Term<'a>> {
+    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
+    let options = WriteOptions::decode(options)?;
+    let _guard = dbr.lock_collection(&collection);
+    let score_type = dbr.score_type(&collection)?;
+    let score = score.map(|s| score_type.decode(s)).transpose()?;
+
+    let score_tree = dbr.tree(SCORE_PREFIX, &collection)?;
+    let key_tree = dbr.tree(KEY_PREFIX, &collection)?;
+
+    (&score_tree, &key_tree)
+        .transaction(|(score_tree, key_tree)| {
+            write_member(score_tree, key_tree, &key, None, score, false, &options)
+        })
+        .map_err(transaction_err_into)?
+        .encode(env, &options, score_type)
+}
+
+// A missing score counts as zero and results outside the score type's range abort with
+// `:overflow`.
+fn incr_member(
+    score_tree: &TransactionalTree,
+    key_tree: &TransactionalTree,
+    key: &[u8],
+    score_type: ScoreType,
+    delta: rustler::Term,
+) -> ConflictableTransactionResult<u64, rustler::error::Error> {
+    let score_key_bytes = key
+        .iter()
+        .chain(SCORE_SUFFIX.iter())
+        .copied()
+        .collect::<Vec<_>>();
+
+    let old_score = match key_tree.get(&score_key_bytes)? {
+        Some(s) => make_u64(&s)?,
+        None => score_type.zero(),
+    };
+    let score = score_type
+        .add(old_score, delta)
+        .map_err(ConflictableTransactionError::Abort)?
+        .ok_or_else(|| {
+            ConflictableTransactionError::Abort(rustler::error::Error::Term(Box::new(
+                atoms::overflow(),
+            )))
+        })?;
+
+    write_member(
+        score_tree,
+        key_tree,
+        key,
+        None,
+        Some(score),
+        true,
+        &WriteOptions::default(),
+    )?;
+    Ok(score)
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn zincrby<'a>(
+    env: rustler::Env<'a>,
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    key: rustler::Binary,
+    delta: rustler::Term<'a>,
+) -> NifResult<rustler::Term<'a>> {
+    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
+    let _guard = dbr.lock_collection(&collection);
+    let score_type = dbr.score_type(&collection)?;
+
+    let score_tree = dbr.tree(SCORE_PREFIX, &collection)?;
+    let key_tree = dbr.tree(KEY_PREFIX, &collection)?;
+
+    let score = (&score_tree, &key_tree)
+        .transaction(|(score_tree, key_tree)| {
+            incr_member(score_tree, key_tree, &key, score_type, delta)
+        })
+        .map_err(transaction_err_into)?;
+    Ok(score_type.encode(env, score))
+}
+
+#[rustler::nif]
+fn zscore<'a>(
+    env: rustler::Env<'a>,
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    key: rustler::Binary,
+) -> NifResult<(bool, Option<rustler::Term<'a>>)> {
+    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
+
+    let key_tree = match dbr.existing_tree(KEY_PREFIX, &collection)? {
+        Some(tree) => tree,
+        None => return Ok((false, None)),
+    };
+    let value_key_bytes = key
+        .as_slice()
+        .to_vec()
+        .into_iter()
+        .chain(VALUE_SUFFIX.iter().copied())
+        .collect::<Vec<_>>();
+
+    let kvvec = IVec::from(value_key_bytes);
+
+    match member_score(&key_tree, &key)? {
+        Some(score) => Ok((true, Some(dbr.score_type(&collection)?.encode(env, score)))),
+        None => match key_tree.get(kvvec.clone()).map_err(sled_err_into)? {
+            Some(_value) => Ok((true, None)),
+            None => Ok((false, None)),
+        },
+    }
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn zrembyrangebyscore<'a>(
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    min_score: rustler::Term<'a>,
+    max_score: rustler::Term<'a>,
+    limit: usize,
+) -> NifResult<u64> {
+    let score_type = collection_score_type(db_resouce, &collection)?;
+    let range = ScoreRange::decode(score_type, min_score, max_score)?;
+    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
+    let guard = dbr.lock_collection(&collection);
+
+    let score_tree = match dbr.existing_tree(SCORE_PREFIX, &collection)? {
+        Some(tree) => tree,
+        None => return Ok(0),
+    };
+    let key_tree = dbr.tree(KEY_PREFIX, &collection)?;
+
+    let score_byte_len = std::mem::size_of::<u64>();
+    let bounds = match range.index_bounds() {
+        Some(bounds) => bounds,
+        None => return Ok(0),
+    };
+    let iter = score_tree.range(bounds).keys();
+
+    let mut iter = iter.take(limit);
+    let mut removed: u64 = 0;
+    loop {
+        let chunk = iter
+            .by_ref()
+            .take(REMOVE_CHUNK_SIZE)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(sled_err_into)?;
+        if chunk.is_empty() {
+            break;
+        }
+
+        removed += (&score_tree, &key_tree)
+            .transaction(|(score_tree, key_tree)| {
+                let mut removed: u64 = 0;
+                for k in &chunk {
+                    // The member was removed or rescored since the range was read.
+                    if score_tree.remove(k)?.is_none() {
+                        continue;
+                    }
+                    let member = index_member(k, score_byte_len)?;
+                    let score_key_bytes = member
+                        .iter()
+                        .copied()
+                        .chain(SCORE_SUFFIX.iter().copied())
+                        .collect::<Vec<_>>();
+                    let value_key_bytes = member
+                        .iter()
+                        .copied()
+                        .chain(VALUE_SUFFIX.iter().copied())
+                        .collect::<Vec<_>>();
+
+                    key_tree.remove(score_key_bytes)?;
+                    key_tree.remove(value_key_bytes)?;
+                    removed += 1;
+                }
+                adjust_card(key_tree, -(removed as i64))?;
+                Ok(removed)
+            })
+            .map_err(transaction_err_into)?;
+    }
+
+    drop(guard);
+    if key_tree.is_empty() {
+        dbr.drop_tree_if_empty(KEY_PREFIX, &collection)?;
+    }
+    if score_tree.is_empty() {
+        dbr.drop_tree_if_empty(SCORE_PREFIX, &collection)?;
+    }
+
+    Ok(removed)
+}
+
+// How a collection's scores are given and returned. Scores are stored as u64 sort keys whose
+// big-endian bytes order like the scores, so the score index, range scans and score comparisons
+// work on sort keys alike for every type.
+#[derive(Clone, Copy, PartialEq)]
+enum ScoreType {
+    U64,
+    I64,
+    F64,
+}
+
+impl ScoreType {
+    fn from_tag(tag: &[u8]) -> Option<Self> {
+        match tag {
+            b"u64" => Some(ScoreType::U64),
+            b"i64" => Some(ScoreType::I64),
+            b"f64" => Some(ScoreType::F64),
+            _ => None,
+        }
+    }
+
+    fn tag(self) -> &'static [u8] {
+        match self {
+            ScoreType::U64 => b"u64",
+            ScoreType::I64 => b"i64",
+            ScoreType::F64 => b"f64",
+        }
+    }
+
+    fn from_atom(atom: rustler::Atom) -> NifResult<Self> {
+        if atom == atoms::u64() {
+            Ok(ScoreType::U64)
+        } else if atom == atoms::i64() {
+            Ok(ScoreType::I64)
+        } else if atom == atoms::f64() {
+            Ok(ScoreType::F64)
+        } else {
+            Err(rustler::Error::BadArg)
+        }
+    }
+
+    fn atom(self) -> rustler::Atom {
+        match self {
+            ScoreType::U64 => atoms::u64(),
+            ScoreType::I64 => atoms::i64(),
+            ScoreType::F64 => atoms::f64(),
+        }
+    }
+
+    // Float collections also take integers. NaN has no place in the order and is refused.
+    fn decode(self, term: rustler::Term) -> NifResult<u64> {
+        match self {
+            ScoreType::U64 => term.decode(),
+            ScoreType::I64 => Ok(i64_sort_key(term.decode()?)),
+            ScoreType::F64 => match decode_f64(term)? {
+                score if score.is_nan() => Err(rustler::Error::BadArg),
+                score => Ok(f64_sort_key(score)),
+            },
+        }
+    }
+
+    fn encode<'a>(self, env: rustler::Env<'a>, sort_key: u64) -> rustler::Term<'a> {
+        match self {
+            ScoreType::U64 => sort_key.encode(env),
+            ScoreType::I64 => i64_from_sort_key(sort_key).encode(env),
+            ScoreType::F64 => f64_from_sort_key(sort_key).encode(env),
+        }
+    }
+
+    fn zero(self) -> u64 {
+        match self {
+            ScoreType::U64 => 0,
+            ScoreType::I64 => i64_sort_key(0),
+            ScoreType::F64 => f64_sort_key(0.0),
+        }
+    }
+
+    // `None` when the result is out of range, which for floats means infinite or NaN since
+    // neither can be returned to Erlang.
+    fn add(self, sort_key: u64, delta: rustler::Term) -> NifResult<Option<u64>> {
+        Ok(match self {
+            ScoreType::U64 => {
+                let delta: i64 = delta.decode()?;
+                if delta >= 0 {
+                    sort_key.checked_add(delta as u64)
+                } else {
+                    sort_key.checked_sub(delta.unsigned_abs())
+                }
+            }
+            ScoreType::I64 => i64_from_sort_key(sort_key)
+                .checked_add(delta.decode()?)
+                .map(i64_sort_key),
+            ScoreType::F64 => match f64_from_sort_key(sort_key) + decode_f64(delta)? {
+                score if score.is_finite() => Some(f64_sort_key(score)),
+                _ => None,
+            },
+        })
+    }
+}
+
+fn decode_f64(term: rustler::Term) -> NifResult<f64> {
+    match term.decode::<f64>() {
+        Ok(score) => Ok(score),
+        Err(_) => Ok(term.decode::<i64>()? as f64),
+    }
+}
+
+// Flipping the sign bit moves negative scores below positive ones.
+fn i64_sort_key(score: i64) -> u64 {
+    score as u64 ^ (1 << 63)
+}
+
+fn i64_from_sort_key(sort_key: u64) -> i64 {
+    (sort_key ^ (1 << 63)) as i64
+}
+
+// IEEE 754 total order: positive floats get the sign bit set and negative floats have every bit
+// flipped, so larger magnitudes of negative floats sort lower. -0.0 is stored as 0.0.
+fn f64_sort_key(score: f64) -> u64 {
+    let bits = (score + 0.0).to_bits();
+    if bits >> 63 == 1 {
+        !bits
+    } else {
+        bits | (1 << 63)
+    }
+}
+
+fn f64_from_sort_key(sort_key: u64) -> f64 {
+    f64::from_bits(if sort_key >> 63 == 1 {
+        sort_key & !(1 << 63)
+    } else {
+        !sort_key
+    })
+}
+
+fn collection_score_type(db_resouce: rustler::Term, collection: &[u8]) -> NifResult<ScoreType> {
+    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
+    dbr.score_type(collection)
+}
+
+// Score bounds accept a plain score (an inclusive minimum or an exclusive maximum), `nil` or an
+// infinity for no bound, and `{:incl, score}` or `{:excl, score}` for an explicit bound.
+#[derive(Clone, Copy)]
+struct ScoreRange {
+    min: Bound<u64>,
+    max: Bound<u64>,
+}
+
+impl ScoreRange {
+    fn decode(score_type: ScoreType, min: rustler::Term, max: rustler::Term) -> NifResult<Self> {
+        Ok(ScoreRange {
+            min: decode_score_bound(score_type, min, true)?,
+            max: decode_score_bound(score_type, max, false)?,
+        })
+    }
+
+    fn contains(&self, score: u64) -> bool {
+        (self.min, self.max).contains(&score)
+    }
+
+    // Bounds over score index keys, which start with the big-endian score. `None` when no
+    // score can match.
+    fn index_bounds(&self) -> Option<(Bound<IVec>, Bound<IVec>)> {
+        let lower = match self.min {
+            Bound::Included(s) => Bound::Included(IVec::from(&s.to_be_bytes())),
+            Bound::Excluded(s) => Bound::Included(IVec::from(&s.checked_add(1)?.to_be_bytes())),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let upper = match self.max {
+            Bound::Included(s) => match s.checked_add(1) {
+                Some(s) => Bound::Excluded(IVec::from(&s.to_be_bytes())),
+                None => Bound::Unbounded,
+            },
+            Bound::Excluded(s) => Bound::Excluded(IVec::from(&s.to_be_bytes())),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        Some((lower, upper))
+    }
+}
+
+fn decode_score_bound(
+    score_type: ScoreType,
+    term: rustler::Term,
+    lower: bool,
+) -> NifResult<Bound<u64>> {
+    if let Ok((kind, score)) = term.decode::<(rustler::Atom, rustler::Term)>() {
+        let score = score_type.decode(score)?;
+        return if kind == atoms::incl() {
+            Ok(Bound::Included(score))
+        } else if kind == atoms::excl() {
+            Ok(Bound::Excluded(score))
+        } else {
+            Err(rustler::Error::BadArg)
+        };
+    }
+    if !term.is_atom() {
+        let score = score_type.decode(term)?;
+        return Ok(if lower {
+            Bound::Included(score)
+        } else {
+            Bound::Excluded(score)
+        });
+    }
+    let infinity: rustler::Atom = term.decode()?;
+    if infinity == rustler::types::atom::nil() {
+        Ok(Bound::Unbounded)
+    } else if infinity == atoms::inf() {
+        Ok(if lower {
+            Bound::Excluded(u64::MAX)
+        } else {
+            Bound::Unbounded
+        })
+    } else if infinity == atoms::neg_inf() {
+        Ok(if lower {
+            Bound::Unbounded
+        } else {
+            Bound::Excluded(0)
+        })
+    } else {
+        Err(rustler::Error::BadArg)
+    }
+}
+
+struct RangeOptions {
+    withscores: bool,
+    withvalues: bool,
+    score_type: ScoreType,
+}
+
+impl RangeOptions {
+    fn new(score_type: ScoreType) -> Self {
+        RangeOptions {
+            withscores: false,
+            withvalues: false,
+            score_type,
+        }
+    }
+
+    fn decode(term: rustler::Term, score_type: ScoreType) -> NifResult<Self> {
+        let mut options = RangeOptions::new(score_type);
+        for (k, v) in decode_options(term)? {
+            if k == atoms::withscores() {
+                options.withscores = v.decode()?;
+            } else if k == atoms::withvalues() {
+                options.withvalues = v.decode()?;
+            } else {
+                return Err(rustler::Error::BadArg);
+            }
+        }
+        Ok(options)
+    }
+
+    // Keys only by default, `{key, score}` with scores and `{key, value, score}` with values.
+    fn encode<'a>(
+        &self,
+        env: rustler::Env<'a>,
+        key_tree: Option<&sled::Tree>,
+        member: &[u8],
+        score: u64,
+    ) -> NifResult<rustler::Term<'a>> {
+        let key = make_binary(env, member)?;
+        let score = self.score_type.encode(env, score);
+        if self.withvalues {
+            let value = match key_tree {
+                Some(tree) => tree
+                    .get(
+                        member
+                            .iter()
+                            .chain(VALUE_SUFFIX.iter())
+                            .copied()
+                            .collect::<Vec<_>>(),
+                    )
+                    .map_err(sled_err_into)?,
+                None => None,
+            };
+            let value = value.map(|v| make_binary(env, &v)).transpose()?;
+            Ok((key, value, score).encode(env))
+        } else if self.withscores {
+            Ok((key, score).encode(env))
+        } else {
+            Ok(key.encode(env))
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn range_by_score<'a>(
+    env: rustler::Env<'a>,
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    range: ScoreRange,
+    offset: usize,
+    limit: usize,
+    rev: bool,
+    options: &RangeOptions,
+) -> NifResult<Vec<rustler::Term<'a>>> {
+    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
+
+    let score_tree = match dbr.existing_tree(SCORE_PREFIX, &collection)? {
+        Some(tree) => tree,
+        None => return Ok(vec![]),
+    };
+    let key_tree = if options.withvalues {
+        dbr.existing_tree(KEY_PREFIX, &collection)?
+    } else {
+        None
+    };
+
+    let score_byte_len = std::mem::size_of::<u64>();
+    let bounds = match range.index_bounds() {
+        Some(bounds) => bounds,
+        None => return Ok(vec![]),
+    };
+    let iter = score_tree.range(bounds).keys();
+    let iter: Box<dyn Iterator<Item = sled::Result<IVec>>> = if rev {
+        Box::new(iter.rev())
+    } else {
+        Box::new(iter)
+    };
+
+    iter.skip(offset)
+        .take(limit)
+        .map(|result| {
+            let result = result.map_err(sled_err_into)?;
+            let member = index_member(&result, score_byte_len)?;
+            let score = make_u64(&result[..score_byte_len])?;
+            options.encode(env, key_tree.as_ref(), member, score)
+        })
+        .collect::<NifResult<Vec<_>>>()
+}
+
+#[allow(clippy::too_many_arguments)]
+#[rustler::nif(schedule = "DirtyIo")]
+fn zrangebyscore<'a>(
+    env: rustler::Env<'a>,
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    min_score: rustler::Term<'a>,
+    max_score: rustler::Term<'a>,
+    offset: usize,
+    limit: usize,
+    opts: rustler::Term<'a>,
+) -> NifResult<Vec<rustler::Term<'a>>> {
+    let score_type = collection_score_type(db_resouce, &collection)?;
+    let range = ScoreRange::decode(score_type, min_score, max_score)?;
+    let options = RangeOptions::decode(opts, score_type)?;
+    range_by_score(
+        env, db_resouce, collection, range, offset, limit, false, &options,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+#[rustler::nif(schedule = "DirtyIo")]
+fn zrevrangebyscore<'a>(
+    env: rustler::Env<'a>,
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    min_score: rustler::Term<'a>,
+    max_score: rustler::Term<'a>,
+    offset: usize,
+    limit: usize,
+    opts: rustler::Term<'a>,
+) -> NifResult<Vec<rustler::Term<'a>>> {
+    let score_type = collection_score_type(db_resouce, &collection)?;
+    let range = ScoreRange::decode(score_type, min_score, max_score)?;
+    let options = RangeOptions::decode(opts, score_type)?;
+    range_by_score(
+        env, db_resouce, collection, range, offset, limit, true, &options,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn range_by_prefix_score<'a>(
+    env: rustler::Env<'a>,
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    prefix: rustler::Binary,
+    range: ScoreRange,
+    offset: usize,
+    limit: usize,
+    rev: bool,
+    options: &RangeOptions,
+) -> NifResult<Vec<rustler::Term<'a>>> {
+    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
+
+    let key_tree = match dbr.existing_tree(KEY_PREFIX, &collection)? {
+        Some(tree) => tree,
+        None => return Ok(vec![]),
+    };
+
+    let scores = prefix_scores(&key_tree, &prefix);
+    let scores: Box<dyn Iterator<Item = Result<(IVec, u64), StoreError>>> = if rev {
+        Box::new(scores.rev())
+    } else {
+        Box::new(scores)
+    };
+
+    let mut matches = Vec::new();
+    for result in scores {
+        let (k, score) = result?;
+        if range.contains(score) {
+            matches.push((k, score));
+        }
+        if matches.len() >= offset.saturating_add(limit) {
+            break;
+        }
+    }
+
+    matches
+        .iter()
+        .skip(offset)
+        .map(|(k, score)| options.encode(env, Some(&key_tree), &k[..(k.len() - 1)], *score))
+        .collect::<NifResult<Vec<_>>>()
+}
+
+#[allow(clippy::too_many_arguments)]
+#[rustler::nif(schedule = "DirtyIo")]
+fn zrangebyprefixscore<'a>(
+    env: rustler::Env<'a>,
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    prefix: rustler::Binary,
+    min_score: rustler::Term<'a>,
+    max_score: rustler::Term<'a>,
+    offset: usize,
+    limit: usize,
+    opts: rustler::Term<'a>,
+) -> NifResult<Vec<rustler::Term<'a>>> {
+    let score_type = collection_score_type(db_resouce, &collection)?;
+    let range = ScoreRange::decode(score_type, min_score, max_score)?;
+    let options = RangeOptions::decode(opts, score_type)?;
+    range_by_prefix_score(
+        env, db_resouce, collection, prefix, range, offset, limit, false, &options,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+#[rustler::nif(schedule = "DirtyIo")]
+fn zrevrangebyprefixscore<'a>(
+    env: rustler::Env<'a>,
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    prefix: rustler::Binary,
+    min_score: rustler::Term<'a>,
+    max_score: rustler::Term<'a>,
+    offset: usize,
+    limit: usize,
+    opts: rustler::Term<'a>,
+) -> NifResult<Vec<rustler::Term<'a>>> {
+    let score_type = collection_score_type(db_resouce, &collection)?;
+    let range = ScoreRange::decode(score_type, min_score, max_score)?;
+    let options = RangeOptions::decode(opts, score_type)?;
+    range_by_prefix_score(
+        env, db_resouce, collection, prefix, range, offset, limit, true, &options,
+    )
 }
 
-fn sled_err_into(e: sled::Error) -> rustler::error::Error {
-    println!("Sled Error: {}", e.to_string());
-    error!("Sled Error: {}", e.to_string());
-    rustler::error::Error::Term(Box::new(atoms::sled_error()))
+// Cursors are the last tree key returned by a scan, so the next page resumes strictly after
+// it instead of re-walking skipped entries. A page shorter than `limit` ends the scan.
+fn make_cursor<'a>(
+    env: rustler::Env<'a>,
+    last: Option<IVec>,
+    len: usize,
+    limit: usize,
+) -> NifResult<Option<rustler::Binary<'a>>> {
+    match last {
+        Some(k) if len >= limit => make_binary(env, &k).map(Some),
+        _ => Ok(None),
+    }
 }
 
-#[rustler::nif]
-fn open<'a>(env: rustler::Env<'a>, a: String) -> NifResult<rustler::Term<'a>> {
-    let config = sled::Config::default().path(&a);
+#[allow(clippy::too_many_arguments)]
+fn scan_by_score<'a>(
+    env: rustler::Env<'a>,
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    range: ScoreRange,
+    cursor: Option<rustler::Binary>,
+    limit: usize,
+    rev: bool,
+    options: &RangeOptions,
+) -> NifResult<(Vec<rustler::Term<'a>>, Option<rustler::Binary<'a>>)> {
+    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
 
-    let db: sled::Db = config.open().map_err(sled_err_into)?;
-    let db_resouce = rustler::ResourceArc::new(DbResource { db: db });
-    Ok((atoms::ok(), db_resouce).encode(env))
+    let score_tree = match dbr.existing_tree(SCORE_PREFIX, &collection)? {
+        Some(tree) => tree,
+        None => return Ok((vec![], None)),
+    };
+    let key_tree = if options.withvalues {
+        dbr.existing_tree(KEY_PREFIX, &collection)?
+    } else {
+        None
+    };
+
+    let score_byte_len = std::mem::size_of::<u64>();
+    let cursor = cursor.map(|c| IVec::from(c.as_slice()));
+    if cursor.as_ref().is_some_and(|c| c.len() < score_byte_len) {
+        return Err(rustler::Error::BadArg);
+    }
+
+    let (mut lower, mut upper) = match range.index_bounds() {
+        Some(bounds) => bounds,
+        None => return Ok((vec![], None)),
+    };
+    match cursor {
+        Some(c) if rev => {
+            upper = match upper {
+                Bound::Excluded(o) if o <= c => Bound::Excluded(o),
+                _ => Bound::Excluded(c),
+            }
+        }
+        Some(c) => {
+            lower = match lower {
+                Bound::Included(m) if m > c => Bound::Included(m),
+                _ => Bound::Excluded(c),
+            }
+        }
+        None => {}
+    }
+    let iter = score_tree.range((lower, upper)).keys();
+    let iter: Box<dyn Iterator<Item = sled::Result<IVec>>> = if rev {
+        Box::new(iter.rev())
+    } else {
+        Box::new(iter)
+    };
+
+    let mut results = Vec::new();
+    let mut last = None;
+    for result in iter.take(limit) {
+        let result = result.map_err(sled_err_into)?;
+        let member = index_member(&result, score_byte_len)?;
+        let score = make_u64(&result[..score_byte_len])?;
+        results.push(options.encode(env, key_tree.as_ref(), member, score)?);
+        last = Some(result);
+    }
+
+    let len = results.len();
+    Ok((results, make_cursor(env, last, len, limit)?))
 }
 
-#[rustler::nif]
-fn clear<'a>(db_resouce: rustler::Term<'a>) -> NifResult<rustler::Atom> {
+#[allow(clippy::too_many_arguments)]
+#[rustler::nif(schedule = "DirtyIo")]
+fn zscanbyscore<'a>(
+    env: rustler::Env<'a>,
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    min_score: rustler::Term<'a>,
+    max_score: rustler::Term<'a>,
+    cursor: Option<rustler::Binary>,
+    limit: usize,
+    opts: rustler::Term<'a>,
+) -> NifResult<(Vec<rustler::Term<'a>>, Option<rustler::Binary<'a>>)> {
+    let score_type = collection_score_type(db_resouce, &collection)?;
+    let range = ScoreRange::decode(score_type, min_score, max_score)?;
+    let options = RangeOptions::decode(opts, score_type)?;
+    scan_by_score(
+        env, db_resouce, collection, range, cursor, limit, false, &options,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+#[rustler::nif(schedule = "DirtyIo")]
+fn zrevscanbyscore<'a>(
+    env: rustler::Env<'a>,
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    min_score: rustler::Term<'a>,
+    max_score: rustler::Term<'a>,
+    cursor: Option<rustler::Binary>,
+    limit: usize,
+    opts: rustler::Term<'a>,
+) -> NifResult<(Vec<rustler::Term<'a>>, Option<rustler::Binary<'a>>)> {
+    let score_type = collection_score_type(db_resouce, &collection)?;
+    let range = ScoreRange::decode(score_type, min_score, max_score)?;
+    let options = RangeOptions::decode(opts, score_type)?;
+    scan_by_score(
+        env, db_resouce, collection, range, cursor, limit, true, &options,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn scan_by_prefix_score<'a>(
+    env: rustler::Env<'a>,
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    prefix: rustler::Binary,
+    range: ScoreRange,
+    cursor: Option<rustler::Binary>,
+    limit: usize,
+    rev: bool,
+    options: &RangeOptions,
+) -> NifResult<(Vec<rustler::Term<'a>>, Option<rustler::Binary<'a>>)> {
     let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
-    let db = &dbr.db;
 
-    db.clear().map_err(sled_err_into)?;
+    let key_tree = match dbr.existing_tree(KEY_PREFIX, &collection)? {
+        Some(tree) => tree,
+        None => return Ok((vec![], None)),
+    };
 
-    for name in db.tree_names() {
-        if name
-            != IVec::from(vec![
-                95, 95, 115, 108, 101, 100, 95, 95, 100, 101, 102, 97, 117, 108, 116,
-            ])
-        {
-            db.drop_tree(name).map_err(sled_err_into)?;
+    let prefix = prefix.as_slice();
+    let cursor = cursor.map(|c| IVec::from(c.as_slice()));
+    if cursor.as_ref().is_some_and(|c| !c.starts_with(prefix)) {
+        return Err(rustler::Error::BadArg);
+    }
+
+    let iter: Box<dyn Iterator<Item = sled::Result<(IVec, IVec)>>> = match cursor {
+        Some(c) if rev => Box::new(
+            key_tree
+                .range((Bound::Included(IVec::from(prefix)), Bound::Excluded(c)))
+                .rev(),
+        ),
+        Some(c) => Box::new(key_tree.range((Bound::Excluded(c), Bound::Unbounded))),
+        None if rev => Box::new(key_tree.scan_prefix(prefix).rev()),
+        None => Box::new(key_tree.scan_prefix(prefix)),
+    };
+
+    let mut results = Vec::new();
+    let mut last = None;
+    for result in iter {
+        let (k, v) = result.map_err(sled_err_into)?;
+        if !k.starts_with(prefix) {
+            break;
+        }
+        if k.last() != SCORE_SUFFIX.last() {
+            continue;
+        }
+        let score = make_u64(&v)?;
+        if range.contains(score) {
+            results.push(options.encode(env, Some(&key_tree), &k[..(k.len() - 1)], score)?);
+            last = Some(k);
+        }
+        if results.len() >= limit {
+            break;
         }
     }
 
+    let len = results.len();
+    Ok((results, make_cursor(env, last, len, limit)?))
+}
+
+#[allow(clippy::too_many_arguments)]
+#[rustler::nif(schedule = "DirtyIo")]
+fn zscanbyprefixscore<'a>(
+    env: rustler::Env<'a>,
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    prefix: rustler::Binary,
+    min_score: rustler::Term<'a>,
+    max_score: rustler::Term<'a>,
+    cursor: Option<rustler::Binary>,
+    limit: usize,
+    opts: rustler::Term<'a>,
+) -> NifResult<(Vec<rustler::Term<'a>>, Option<rustler::Binary<'a>>)> {
+    let score_type = collection_score_type(db_resouce, &collection)?;
+    let range = ScoreRange::decode(score_type, min_score, max_score)?;
+    let options = RangeOptions::decode(opts, score_type)?;
+    scan_by_prefix_score(
+        env, db_resouce, collection, prefix, range, cursor, limit, false, &options,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+#[rustler::nif(schedule = "DirtyIo")]
+fn zrevscanbyprefixscore<'a>(
+    env: rustler::Env<'a>,
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    prefix: rustler::Binary,
+    min_score: rustler::Term<'a>,
+    max_score: rustler::Term<'a>,
+    cursor: Option<rustler::Binary>,
+    limit: usize,
+    opts: rustler::Term<'a>,
+) -> NifResult<(Vec<rustler::Term<'a>>, Option<rustler::Binary<'a>>)> {
+    let score_type = collection_score_type(db_resouce, &collection)?;
+    let range = ScoreRange::decode(score_type, min_score, max_score)?;
+    let options = RangeOptions::decode(opts, score_type)?;
+    scan_by_prefix_score(
+        env, db_resouce, collection, prefix, range, cursor, limit, true, &options,
+    )
+}
+
+#[rustler::nif]
+fn zscoretype<'a>(
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+) -> NifResult<rustler::Atom> {
+    Ok(collection_score_type(db_resouce, &collection)?.atom())
+}
+
+// Stored sort keys only mean something for the type they were written with, so the type can
+// only change while the collection has no members. Blocking pops decode scores with the type
+// they started with, so it cannot change while any wait on the collection either.
+#[rustler::nif(schedule = "DirtyIo")]
+fn zsetscoretype<'a>(
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    score_type: rustler::Atom,
+) -> NifResult<rustler::Atom> {
+    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
+    let score_type = ScoreType::from_atom(score_type)?;
+    let _guard = dbr.lock_collection_exclusive(&collection);
+
+    if dbr.score_type(&collection)? == score_type {
+        return Ok(atoms::ok());
+    }
+    if let Some(key_tree) = dbr.existing_tree(KEY_PREFIX, &collection)? {
+        if member_count(&key_tree)? > 0 {
+            return Err(rustler::error::Error::Term(Box::new(atoms::not_empty())));
+        }
+    }
+    if dbr.is_watched(SCORE_PREFIX, &collection) {
+        return Err(rustler::error::Error::Term(Box::new(atoms::busy())));
+    }
+    dbr.set_score_type(&collection, score_type)?;
     Ok(atoms::ok())
 }
 
 #[rustler::nif]
-fn zadd<'a>(
+fn zcard<'a>(db_resouce: rustler::Term<'a>, collection: rustler::Binary) -> NifResult<u64> {
+    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
+
+    let key_tree = match dbr.existing_tree(KEY_PREFIX, &collection)? {
+        Some(tree) => tree,
+        None => return Ok(0),
+    };
+    Ok(member_count(&key_tree)?)
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn zcount<'a>(
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    min_score: rustler::Term<'a>,
+    max_score: rustler::Term<'a>,
+) -> NifResult<u64> {
+    let score_type = collection_score_type(db_resouce, &collection)?;
+    let range = ScoreRange::decode(score_type, min_score, max_score)?;
+    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
+
+    let score_tree = match dbr.existing_tree(SCORE_PREFIX, &collection)? {
+        Some(tree) => tree,
+        None => return Ok(0),
+    };
+    let bounds = match range.index_bounds() {
+        Some(bounds) => bounds,
+        None => return Ok(0),
+    };
+
+    let mut count = 0;
+    for k in score_tree.range(bounds).keys() {
+        k.map_err(sled_err_into)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[rustler::nif]
+fn zexists<'a>(
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    min_score: rustler::Term<'a>,
+    max_score: rustler::Term<'a>,
+) -> NifResult<bool> {
+    let score_type = collection_score_type(db_resouce, &collection)?;
+    let range = ScoreRange::decode(score_type, min_score, max_score)?;
+    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
+
+    let score_tree = match dbr.existing_tree(SCORE_PREFIX, &collection)? {
+        Some(tree) => tree,
+        None => return Ok(false),
+    };
+
+    let bounds = match range.index_bounds() {
+        Some(bounds) => bounds,
+        None => return Ok(false),
+    };
+    let mut iter = score_tree.range(bounds).keys();
+    Ok(iter.next().transpose().map_err(sled_err_into)?.is_some())
+}
+
+#[rustler::nif]
+fn zgetbykey<'a>(
+    env: rustler::Env<'a>,
     db_resouce: rustler::Term<'a>,
     collection: rustler::Binary,
     key: rustler::Binary,
-    value: Option<rustler::Binary>,
-    score: Option<u64>,
-    gt: bool,
-) -> NifResult<rustler::Atom> {
+    min_score: rustler::Term<'a>,
+) -> NifResult<Entry<'a>> {
     let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
-    let db = &dbr.db;
-    let score_tree_bytes = SCORE_PREFIX
+
+    let key_tree = match dbr.existing_tree(KEY_PREFIX, &collection)? {
+        Some(tree) => tree,
+        None => return Ok(None),
+    };
+    let score_type = dbr.score_type(&collection)?;
+    let min_score = score_type.decode(min_score)?;
+
+    let value_key_bytes = key
+        .as_slice()
         .to_vec()
         .into_iter()
-        .chain(collection.as_slice().iter().copied())
+        .chain(VALUE_SUFFIX.iter().copied())
         .collect::<Vec<_>>();
-    let key_tree_bytes = KEY_PREFIX
-        .to_vec()
+
+    let kvvec = IVec::from(value_key_bytes);
+    let value = key_tree.get(kvvec.clone()).map_err(sled_err_into)?;
+    let score_dec = member_score(&key_tree, &key)?;
+    let value_dec = value.map(|v| make_binary(env, &v)).transpose()?;
+    if value_dec.is_some() || score_dec.is_some() {
+        if score_dec.unwrap_or(u64::MAX) >= min_score {
+            let score = score_dec.map(|score| score_type.encode(env, score));
+            Ok(Some((value_dec, score)))
+        } else {
+            Ok(None)
+        }
+    } else {
+        Ok(None)
+    }
+}
+
+// Reads the value and score of each key, reusing one buffer for the lookup keys.
+fn read_members(
+    key_tree: &sled::Tree,
+    keys: &[rustler::Binary],
+) -> NifResult<Vec<(Option<IVec>, Option<IVec>)>> {
+    let mut lookup = Vec::new();
+    keys.iter()
+        .map(|key| {
+            lookup.clear();
+            lookup.extend_from_slice(key);
+            lookup.extend_from_slice(VALUE_SUFFIX);
+            let value = key_tree.get(&lookup).map_err(sled_err_into)?;
+            lookup.truncate(key.len());
+            lookup.extend_from_slice(SCORE_SUFFIX);
+            let score = key_tree.get(&lookup).map_err(sled_err_into)?;
+            Ok((value, score))
+        })
+        .collect()
+}
+
+// Copies the values into a single binary and returns sub-binaries of it, one allocation for the
+// whole batch. Each sub-binary keeps the shared binary alive.
+fn make_binaries<'a>(
+    env: rustler::Env<'a>,
+    values: &[Option<IVec>],
+) -> NifResult<Vec<Option<rustler::Binary<'a>>>> {
+    let total = values.iter().flatten().map(|v| v.len()).sum();
+    let mut bin = rustler::OwnedBinary::new(total)
+        .ok_or_else(|| rustler::error::Error::Term(Box::new(atoms::out_of_memory())))?;
+    let mut offset = 0;
+    for value in values.iter().flatten() {
+        bin.as_mut_slice()[offset..offset + value.len()].copy_from_slice(value);
+        offset += value.len();
+    }
+
+    let bin = bin.release(env);
+    let mut offset = 0;
+    values
+        .iter()
+        .map(|value| {
+            value
+                .as_ref()
+                .map(|value| {
+                    let sub = bin.make_subbinary(offset, value.len());
+                    offset += value.len();
+                    sub
+                })
+                .transpose()
+        })
+        .collect()
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn zmget<'a>(
+    env: rustler::Env<'a>,
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    keys: Vec<rustler::Binary>,
+    min_score: rustler::Term<'a>,
+) -> NifResult<Vec<Entry<'a>>> {
+    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
+
+    let key_tree = match dbr.existing_tree(KEY_PREFIX, &collection)? {
+        Some(tree) => tree,
+        None => return Ok(vec![None; keys.len()]),
+    };
+    let score_type = dbr.score_type(&collection)?;
+    let min_score = score_type.decode(min_score)?;
+
+    let (values, scores): (Vec<_>, Vec<_>) = read_members(&key_tree, &keys)?.into_iter().unzip();
+    let values = make_binaries(env, &values)?;
+    values
+        .into_iter()
+        .zip(scores)
+        .map(|(value, score)| {
+            let score = score.map(|s| make_u64(&s)).transpose()?;
+            if (value.is_some() || score.is_some()) && score.unwrap_or(u64::MAX) >= min_score {
+                Ok(Some((value, score.map(|s| score_type.encode(env, s)))))
+            } else {
+                Ok(None)
+            }
+        })
+        .collect()
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn zmscore<'a>(
+    env: rustler::Env<'a>,
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    keys: Vec<rustler::Binary>,
+) -> NifResult<Vec<(bool, Option<rustler::Term<'a>>)>> {
+    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
+
+    let key_tree = match dbr.existing_tree(KEY_PREFIX, &collection)? {
+        Some(tree) => tree,
+        None => return Ok(vec![(false, None); keys.len()]),
+    };
+
+    let score_type = dbr.score_type(&collection)?;
+    read_members(&key_tree, &keys)?
         .into_iter()
-        .chain(collection.as_slice().iter().copied())
+        .map(|(value, score)| {
+            let exists = value.is_some() || score.is_some();
+            Ok((exists, make_score(env, score.as_deref(), score_type)?))
+        })
+        .collect()
+}
+
+// Removes a member's value, score and index entry, returning the value and score it had.
+fn delete_member(
+    score_tree: &TransactionalTree,
+    key_tree: &TransactionalTree,
+    key: &[u8],
+) -> ConflictableTransactionResult<(Option<IVec>, Option<IVec>), rustler::error::Error> {
+    let value_key_bytes = key
+        .iter()
+        .copied()
+        .chain(VALUE_SUFFIX.iter().copied())
+        .collect::<Vec<_>>();
+    let score_key_bytes = key
+        .iter()
+        .copied()
+        .chain(SCORE_SUFFIX.iter().copied())
         .collect::<Vec<_>>();
 
-    let score_tree: sled::Tree = db.open_tree(IVec::from(score_tree_bytes)).unwrap();
-    let key_tree: sled::Tree = db.open_tree(IVec::from(key_tree_bytes)).unwrap();
+    let value = key_tree.remove(value_key_bytes)?;
+    let score = key_tree.remove(score_key_bytes)?;
+
+    if let Some(s) = &score {
+        let score_bytes = s.iter().chain(key).copied().collect::<Vec<_>>();
+        score_tree.remove(score_bytes)?;
+    }
+    if value.is_some() || score.is_some() {
+        adjust_card(key_tree, -1)?;
+    }
+
+    Ok((value, score))
+}
+
+fn remove_member(
+    dbr: &DbResource,
+    collection: &[u8],
+    key: &[u8],
+) -> NifResult<(Option<IVec>, Option<IVec>)> {
+    let guard = dbr.lock_collection(collection);
+    let key_tree = match dbr.existing_tree(KEY_PREFIX, collection)? {
+        Some(tree) => tree,
+        None => return Ok((None, None)),
+    };
+    let score_tree = dbr.tree(SCORE_PREFIX, collection)?;
+
+    let removed = (&score_tree, &key_tree)
+        .transaction(|(score_tree, key_tree)| delete_member(score_tree, key_tree, key))
+        .map_err(transaction_err_into)?;
+
+    drop(guard);
+    if key_tree.is_empty() {
+        dbr.drop_tree_if_empty(KEY_PREFIX, collection)?;
+    }
+    if score_tree.is_empty() {
+        dbr.drop_tree_if_empty(SCORE_PREFIX, collection)?;
+    }
+
+    Ok(removed)
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn zrem<'a>(
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    key: rustler::Binary,
+) -> NifResult<rustler::Atom> {
+    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
+    remove_member(&dbr, &collection, &key)?;
+
+    Ok(atoms::ok())
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn zpopkey<'a>(
+    env: rustler::Env<'a>,
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    key: rustler::Binary,
+) -> NifResult<Entry<'a>> {
+    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
+    let score_type = dbr.score_type(&collection)?;
+    let (value, score) = remove_member(&dbr, &collection, &key)?;
+
+    make_entry(env, value, score, score_type)
+}
+
+// Pops members from the low or high end of the score index, retrying when other writers remove
+// the members read from the index before the transaction commits. Returns the index keys of
+// the popped members with their values.
+fn pop_members(
+    dbr: &DbResource,
+    collection: &[u8],
+    count: usize,
+    range: ScoreRange,
+    rev: bool,
+) -> NifResult<Vec<(IVec, Option<IVec>)>> {
+    let guard = dbr.lock_collection(collection);
+
+    let score_tree = match dbr.existing_tree(SCORE_PREFIX, collection)? {
+        Some(tree) => tree,
+        None => return Ok(vec![]),
+    };
+    let key_tree = dbr.tree(KEY_PREFIX, collection)?;
+    let bounds = match range.index_bounds() {
+        Some(bounds) => bounds,
+        None => return Ok(vec![]),
+    };
+    let score_byte_len = std::mem::size_of::<u64>();
+
+    let mut popped = Vec::new();
+    while popped.len() < count {
+        let iter = score_tree.range(bounds.clone()).keys();
+        let iter: Box<dyn Iterator<Item = sled::Result<IVec>>> = if rev {
+            Box::new(iter.rev())
+        } else {
+            Box::new(iter)
+        };
+        let candidates = iter
+            .take(count - popped.len())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(sled_err_into)?;
+        if candidates.is_empty() {
+            break;
+        }
+
+        let chunk = (&score_tree, &key_tree)
+            .transaction(|(score_tree, key_tree)| {
+                let mut chunk = Vec::new();
+                for k in &candidates {
+                    if score_tree.remove(k)?.is_none() {
+                        continue;
+                    }
+                    let member = index_member(k, score_byte_len)?;
+                    let score_key_bytes = member
+                        .iter()
+                        .copied()
+                        .chain(SCORE_SUFFIX.iter().copied())
+                        .collect::<Vec<_>>();
+                    let value_key_bytes = member
+                        .iter()
+                        .copied()
+                        .chain(VALUE_SUFFIX.iter().copied())
+                        .collect::<Vec<_>>();
+
+                    key_tree.remove(score_key_bytes)?;
+                    let value = key_tree.remove(value_key_bytes)?;
+                    chunk.push((k.clone(), value));
+                }
+                adjust_card(key_tree, -(chunk.len() as i64))?;
+                Ok(chunk)
+            })
+            .map_err(transaction_err_into)?;
+        popped.extend(chunk);
+    }
+
+    drop(guard);
+    if key_tree.is_empty() {
+        dbr.drop_tree_if_empty(KEY_PREFIX, collection)?;
+    }
+    if score_tree.is_empty() {
+        dbr.drop_tree_if_empty(SCORE_PREFIX, collection)?;
+    }
+
+    Ok(popped)
+}
+
+// `{key, value, score}` of a popped member.
+type PoppedMember<'a> = (
+    rustler::Binary<'a>,
+    Option<rustler::Binary<'a>>,
+    rustler::Term<'a>,
+);
+
+fn make_popped_member<'a>(
+    env: rustler::Env<'a>,
+    index_key: &[u8],
+    value: Option<&IVec>,
+    score_type: ScoreType,
+) -> NifResult<PoppedMember<'a>> {
+    let score_byte_len = std::mem::size_of::<u64>();
+    let member = make_binary(env, index_member(index_key, score_byte_len)?)?;
+    let value = value.map(|v| make_binary(env, v)).transpose()?;
+    let score = make_u64(&index_key[..score_byte_len])?;
+    Ok((member, value, score_type.encode(env, score)))
+}
+
+fn pop_by_score<'a>(
+    env: rustler::Env<'a>,
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    count: usize,
+    max_score: rustler::Term<'a>,
+    rev: bool,
+) -> NifResult<Vec<PoppedMember<'a>>> {
+    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
+    let score_type = dbr.score_type(&collection)?;
+    let range = ScoreRange {
+        min: Bound::Unbounded,
+        max: decode_score_bound(score_type, max_score, false)?,
+    };
+
+    pop_members(&dbr, &collection, count, range, rev)?
+        .iter()
+        .map(|(k, value)| make_popped_member(env, k, value.as_ref(), score_type))
+        .collect::<NifResult<Vec<_>>>()
+}
 
-    (&score_tree, &key_tree)
-        .transaction(|(score_tree, key_tree)| {
-            let score_bytes_key = score.map(|v| {
-                v.to_be_bytes()
-                    .to_vec()
-                    .into_iter()
-                    .chain(key.as_slice().iter().copied())
-                    .collect::<Vec<_>>()
-            });
+#[rustler::nif(schedule = "DirtyIo")]
+fn zpopmin<'a>(
+    env: rustler::Env<'a>,
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    count: usize,
+    max_score: rustler::Term<'a>,
+) -> NifResult<Vec<PoppedMember<'a>>> {
+    pop_by_score(env, db_resouce, collection, count, max_score, false)
+}
 
-            let value_key_bytes = key
-                .as_slice()
-                .to_vec()
-                .into_iter()
-                .chain(VALUE_SUFFIX.iter().copied())
-                .collect::<Vec<_>>();
+#[rustler::nif(schedule = "DirtyIo")]
+fn zpopmax<'a>(
+    env: rustler::Env<'a>,
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    count: usize,
+    max_score: rustler::Term<'a>,
+) -> NifResult<Vec<PoppedMember<'a>>> {
+    pop_by_score(env, db_resouce, collection, count, max_score, true)
+}
 
-            let score_key_bytes = key
-                .as_slice()
-                .to_vec()
-                .into_iter()
-                .chain(SCORE_SUFFIX.iter().copied())
-                .collect::<Vec<_>>();
+// Right pushes count up from the middle of the i64 range and left pushes count down from it, so
+// the big-endian keys order the list from its head to its tail.
+// Returns the key the element was stored under.
+fn push_list(
+    list_tree: &TransactionalTree,
+    value: &[u8],
+    left: bool,
+) -> ConflictableTransactionResult<[u8; 8], rustler::error::Error> {
+    let right_side_id = list_tree.generate_id()?;
+    let key = (right_side_id as i128 - i64::MAX as i128) as i64;
+    let key = if left { -key } else { key }.to_be_bytes();
+
+    list_tree.insert(&key, value)?;
+    adjust_card(list_tree, 1)?;
+    Ok(key)
+}
 
-            let kvvec = IVec::from(value_key_bytes);
-            let ksvec = IVec::from(score_key_bytes);
-            let mut insert = false;
-            let mut old_value: Option<IVec> = None;
-
-            match key_tree.get(ksvec.clone())? {
-                Some(value) => {
-                    old_value = Some(value.clone());
-                    if gt {
-                        let old_score: u64 = make_u64(&value);
-                        if score.unwrap_or(u64::MAX) > old_score {
-                            insert = true
-                        }
-                    } else {
-                        insert = true
-                    }
-                }
-                _ => insert = true,
-            }
+#[rustler::nif(schedule = "DirtyIo")]
+fn rpush<'a>(
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    value: rustler::Binary,
+) -> NifResult<rustler::Atom> {
+    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
+    let _guard = dbr.lock_collection(&collection);
 
-            if insert {
-                if let Some(v) = value {
-                    key_tree.insert(kvvec, v.as_slice())?;
-                } else {
-                    key_tree.remove(kvvec)?;
-                }
-                if let Some(s) = score {
-                    let score_bin = s.to_be_bytes();
-                    key_tree.insert(ksvec, &score_bin)?;
-                } else {
-                    key_tree.remove(ksvec)?;
-                }
-                if let Some(b) = score_bytes_key {
-                    score_tree.insert(b, b"")?;
-                }
-                if let Some(old) = old_value {
-                    let old_score_bytes = old
-                        .to_vec()
-                        .into_iter()
-                        .chain(key.as_slice().iter().copied())
-                        .collect::<Vec<_>>();
-                    score_tree.remove(old_score_bytes)?;
-                }
-            }
+    let list_tree = dbr.tree(LIST_PREFIX, &collection)?;
 
-            Ok(atoms::ok())
-        })
-        .map_err(io_err_into)
+    list_tree
+        .transaction(|list_tree| push_list(list_tree, &value, false))
+        .map_err(transaction_err_into)?;
+
+    Ok(atoms::ok())
 }
 
-#[rustler::nif]
-fn zscoreupdate<'a>(
+#[rustler::nif(schedule = "DirtyIo")]
+fn lpush<'a>(
     db_resouce: rustler::Term<'a>,
     collection: rustler::Binary,
-    key: rustler::Binary,
-    score: Option<u64>,
-    gt: bool,
+    value: rustler::Binary,
 ) -> NifResult<rustler::Atom> {
     let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
-    let db = &dbr.db;
-    let score_tree_bytes = SCORE_PREFIX
-        .to_vec()
-        .into_iter()
-        .chain(collection.as_slice().iter().copied())
-        .collect::<Vec<_>>();
-    let key_tree_bytes = KEY_PREFIX
-        .to_vec()
-        .into_iter()
-        .chain(collection.as_slice().iter().copied())
-        .collect::<Vec<_>>();
+    let _guard = dbr.lock_collection(&collection);
 
-    let score_tree: sled::Tree = db.open_tree(IVec::from(score_tree_bytes)).unwrap();
-    let key_tree: sled::Tree = db.open_tree(IVec::from(key_tree_bytes)).unwrap();
+    let list_tree = dbr.tree(LIST_PREFIX, &collection)?;
 
-    (&score_tree, &key_tree)
-        .transaction(|(score_tree, key_tree)| {
-            let score_key_bytes = key
-                .as_slice()
-                .to_vec()
-                .into_iter()
-                .chain(SCORE_SUFFIX.iter().copied())
-                .collect::<Vec<_>>();
+    list_tree
+        .transaction(|list_tree| push_list(list_tree, &value, true))
+        .map_err(transaction_err_into)?;
 
-            let ksvec = IVec::from(score_key_bytes);
+    Ok(atoms::ok())
+}
 
-            if let Some(value) = key_tree.get(ksvec.clone())? {
-                let old_score: u64 = make_u64(&value);
+fn list_elements(list_tree: &sled::Tree) -> sled::Iter {
+    list_tree.range::<&[u8], _>((Bound::Excluded(&CARD_KEY[..]), Bound::Unbounded))
+}
 
-                let old_score_bytes = old_score
-                    .to_be_bytes()
-                    .to_vec()
-                    .into_iter()
-                    .chain(key.as_slice().iter().copied())
-                    .collect::<Vec<_>>();
+fn pop_list(dbr: &DbResource, collection: &[u8], rev: bool) -> NifResult<Option<(IVec, IVec)>> {
+    let guard = dbr.lock_collection(collection);
 
-                if let Some(s) = score {
-                    let score_bytes = s
-                        .to_be_bytes()
-                        .to_vec()
-                        .into_iter()
-                        .chain(key.as_slice().iter().copied())
-                        .collect::<Vec<_>>();
+    let list_tree = match dbr.existing_tree(LIST_PREFIX, collection)? {
+        Some(tree) => tree,
+        None => return Ok(None),
+    };
 
-                    let should_commit = !gt || s > old_score;
-                    if should_commit {
-                        score_tree.remove(old_score_bytes)?;
-                        score_tree.insert(score_bytes, b"")?;
-                        key_tree.insert(ksvec, &s.to_be_bytes())?;
-                    }
-                } else {
-                    key_tree.remove(ksvec)?;
-                    score_tree.remove(old_score_bytes)?;
+    // Retries when another pop removes the element first.
+    let maybe_elem = loop {
+        let mut elements = list_elements(&list_tree).keys();
+        let next = if rev {
+            elements.next_back()
+        } else {
+            elements.next()
+        };
+        let k = match next {
+            Some(k) => k.map_err(sled_err_into)?,
+            None => break None,
+        };
+        let popped = list_tree
+            .transaction(|list_tree| match list_tree.remove(&k)? {
+                Some(elem) => {
+                    adjust_card(list_tree, -1)?;
+                    Ok(Some((k.clone(), elem)))
                 }
+                None => Ok(None),
+            })
+            .map_err(transaction_err_into)?;
+        if popped.is_some() {
+            break popped;
+        }
+    };
+    drop(guard);
+    if list_tree.is_empty() {
+        dbr.drop_tree_if_empty(LIST_PREFIX, collection)?;
+    }
+    Ok(maybe_elem)
+}
+
+enum Popped {
+    // A list element with its list key.
+    Element(IVec, IVec),
+    // A sorted set member with its score index key, value and the collection's score type.
+    Member(IVec, Option<IVec>, ScoreType),
+}
+
+impl Popped {
+    fn encode<'a>(&self, env: rustler::Env<'a>) -> NifResult<rustler::Term<'a>> {
+        match self {
+            Popped::Element(_, elem) => Ok(make_binary(env, elem)?.encode(env)),
+            Popped::Member(index_key, value, score_type) => {
+                Ok(make_popped_member(env, index_key, value.as_ref(), *score_type)?.encode(env))
             }
+        }
+    }
 
-            Ok(atoms::ok())
-        })
-        .map_err(io_err_into)
+    // Puts an entry back where it was popped from. A member added again since is left as it is.
+    fn restore(&self, dbr: &DbResource, collection: &[u8]) -> NifResult<()> {
+        let _guard = dbr.lock_collection(collection);
+        match self {
+            Popped::Element(list_key, elem) => {
+                dbr.tree(LIST_PREFIX, collection)?
+                    .transaction(|list_tree| {
+                        list_tree.insert(list_key, elem)?;
+                        adjust_card(list_tree, 1)
+                    })
+                    .map_err(transaction_err_into)?;
+            }
+            Popped::Member(index_key, value, _) => {
+                let score_byte_len = std::mem::size_of::<u64>();
+                let member = index_member(index_key, score_byte_len)?;
+                let score = make_u64(&index_key[..score_byte_len])?;
+                let options = WriteOptions {
+                    nx: true,
+                    ..Default::default()
+                };
+                let score_tree = dbr.tree(SCORE_PREFIX, collection)?;
+                let key_tree = dbr.tree(KEY_PREFIX, collection)?;
+                (&score_tree, &key_tree)
+                    .transaction(|(score_tree, key_tree)| {
+                        write_member(
+                            score_tree,
+                            key_tree,
+                            member,
+                            Some(value.as_deref()),
+                            Some(score),
+                            true,
+                            &options,
+                        )
+                    })
+                    .map_err(transaction_err_into)?;
+            }
+        }
+        Ok(())
+    }
 }
 
-#[rustler::nif]
-fn zscore<'a>(
-    db_resouce: rustler::Term<'a>,
-    collection: rustler::Binary,
-    key: rustler::Binary,
-) -> NifResult<(bool, Option<u64>)> {
-    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
-    let db = &dbr.db;
-    let key_tree_bytes = KEY_PREFIX
-        .to_vec()
-        .into_iter()
-        .chain(collection.as_slice().iter().copied())
-        .collect::<Vec<_>>();
+fn error_reason<'a>(env: rustler::Env<'a>, e: rustler::error::Error) -> rustler::Term<'a> {
+    match e {
+        rustler::error::Error::Term(reason) => reason.encode(env),
+        _ => rustler::types::atom::badarg().encode(env),
+    }
+}
 
-    let key_tree: sled::Tree = db.open_tree(IVec::from(key_tree_bytes)).unwrap();
-    let score_key_bytes = key
-        .as_slice()
-        .to_vec()
-        .into_iter()
-        .chain(SCORE_SUFFIX.iter().copied())
-        .collect::<Vec<_>>();
-    let value_key_bytes = key
-        .as_slice()
-        .to_vec()
-        .into_iter()
-        .chain(VALUE_SUFFIX.iter().copied())
-        .collect::<Vec<_>>();
+fn process_alive(msg_env: &OwnedEnv, pid: &rustler::LocalPid) -> bool {
+    msg_env.run(|env| unsafe {
+        rustler_sys::enif_is_process_alive(env.as_c_arg(), pid.as_c_arg()) != 0
+    })
+}
 
-    let ksvec = IVec::from(score_key_bytes);
-    let kvvec = IVec::from(value_key_bytes);
+// Like `OwnedEnv::send_and_clear`, but returns false when the process was not alive to receive
+// `{:sortedsetkv, ref, message}`.
+fn send_reply<F>(
+    msg_env: OwnedEnv,
+    pid: &rustler::LocalPid,
+    reference: &rustler::env::SavedTerm,
+    message: F,
+) -> bool
+where
+    F: for<'a> FnOnce(rustler::Env<'a>) -> rustler::Term<'a>,
+{
+    msg_env.run(|env| {
+        let message = (atoms::sortedsetkv(), reference.load(env), message(env)).encode(env);
+        unsafe {
+            rustler_sys::enif_send(
+                std::ptr::null_mut(),
+                pid.as_c_arg(),
+                env.as_c_arg(),
+                message.as_c_arg(),
+            ) != 0
+        }
+    })
+}
 
-    match key_tree.get(ksvec.clone()).map_err(sled_err_into)? {
-        Some(value) => {
-            let score: u64 = make_u64(&value);
-            Ok((true, Some(score)))
+// Returns the result with the cancel flag still locked, so a racing `bcancel` waits until the
+// result has been sent, or `None` once cancelled or once the caller is gone.
+fn wait_and_pop<'w, F, A>(
+    dbr: &DbResource,
+    collection: &[u8],
+    subscriber: &mut sled::Subscriber,
+    timeout: Duration,
+    cancelled: &'w Mutex<bool>,
+    pop: &F,
+    caller_alive: A,
+) -> Option<(MutexGuard<'w, bool>, NifResult<Option<Popped>>)>
+where
+    F: Fn(&DbResource, &[u8]) -> NifResult<Option<Popped>>,
+    A: Fn() -> bool,
+{
+    let deadline = Instant::now() + timeout;
+    loop {
+        let guard = cancelled.lock().unwrap_or_else(|e| e.into_inner());
+        if *guard || !caller_alive() {
+            return None;
+        }
+        let now = Instant::now();
+        match dbr.db().and_then(|_| pop(dbr, collection)) {
+            Ok(None) if now < deadline => drop(guard),
+            result => return Some((guard, result)),
+        }
+        let wait = (deadline - now).min(Duration::from_millis(BLOCKING_POLL_MS));
+        if let Err(RecvTimeoutError::Disconnected) = subscriber.next_timeout(wait) {
+            std::thread::sleep(wait);
         }
-        None => match key_tree.get(kvvec.clone()).map_err(sled_err_into)? {
-            Some(_value) => Ok((true, None)),
-            None => Ok((false, None)),
-        },
     }
 }
 
-#[rustler::nif]
-fn zrembyrangebyscore<'a>(
+// Pops right away when possible. Otherwise a thread waits for changes to the tree and sends
+// `{:sortedsetkv, ref, popped | :timeout | {:error, reason}}` once it pops or times out, unless
+// `bcancel` stops it first. Nothing is popped for a caller that has exited, and an entry popped
+// just before the caller exits is put back.
+fn blocking_pop<'a, F>(
+    env: rustler::Env<'a>,
     db_resouce: rustler::Term<'a>,
+    prefix: &'static [u8],
     collection: rustler::Binary,
-    min_score: u64,
-    max_score: Option<u64>,
-    limit: usize,
-) -> NifResult<u64> {
+    timeout: u64,
+    reference: rustler::Term<'a>,
+    pop: F,
+) -> NifResult<rustler::Atom>
+where
+    F: Fn(&DbResource, &[u8]) -> NifResult<Option<Popped>> + Send + 'static,
+{
     let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
-    let db = &dbr.db;
-    let score_tree_bytes = SCORE_PREFIX
-        .to_vec()
-        .into_iter()
-        .chain(collection.as_slice().iter().copied())
-        .collect::<Vec<_>>();
-    let key_tree_bytes = KEY_PREFIX
-        .to_vec()
-        .into_iter()
-        .chain(collection.as_slice().iter().copied())
-        .collect::<Vec<_>>();
+    let collection = collection.as_slice().to_vec();
 
-    let score_tree: sled::Tree = db.open_tree(IVec::from(score_tree_bytes.clone())).unwrap();
-    let key_tree: sled::Tree = db.open_tree(IVec::from(key_tree_bytes.clone())).unwrap();
+    if let Some(popped) = pop(&dbr, &collection)? {
+        let message = (atoms::sortedsetkv(), reference, popped.encode(env)?).encode(env);
+        env.send(&env.pid(), message);
+        return Ok(atoms::ok());
+    }
 
-    let min_bytes = min_score.to_be_bytes().to_vec();
-    let score_byte_len = min_bytes.clone().len();
+    let waiter = reference.to_binary().as_slice().to_vec();
+    let cancelled = Arc::new(Mutex::new(false));
+    {
+        let mut waiters = dbr.waiters.lock().unwrap_or_else(|e| e.into_inner());
+        if waiters.contains_key(&waiter) {
+            return Err(rustler::Error::BadArg);
+        }
+        if waiters.len() >= MAX_BLOCKING_WAITERS {
+            return Err(rustler::Error::Term(Box::new(atoms::too_many_waiters())));
+        }
+        waiters.insert(waiter.clone(), cancelled.clone());
+    }
 
-    let iter = if let Some(o) = max_score {
-        score_tree
-            .range(IVec::from(min_bytes)..IVec::from(o.to_be_bytes().to_vec()))
-            .keys()
-    } else {
-        score_tree.range(IVec::from(min_bytes)..).keys()
+    let mut subscriber = match dbr.watch_tree(prefix, &collection) {
+        Ok(subscriber) => subscriber,
+        Err(e) => {
+            dbr.waiters
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(&waiter);
+            return Err(e);
+        }
     };
+    let pid = env.pid();
+    let msg_env = OwnedEnv::new();
+    let saved_reference = msg_env.save(reference);
+
+    let waiting = dbr.clone();
+    let waiting_collection = collection.clone();
+    let waiting_key = waiter.clone();
+    let spawned = std::thread::Builder::new().spawn(move || {
+        let timeout = Duration::from_millis(timeout);
+        let result = wait_and_pop(
+            &waiting,
+            &waiting_collection,
+            &mut subscriber,
+            timeout,
+            &cancelled,
+            &pop,
+            || process_alive(&msg_env, &pid),
+        );
+        if let Some((_guard, result)) = result {
+            let (popped, error) = match result {
+                Ok(popped) => (popped, None),
+                Err(e) => (None, Some(e)),
+            };
+            let sent = send_reply(msg_env, &pid, &saved_reference, |env| {
+                match (&popped, error) {
+                    (Some(popped), _) => popped
+                        .encode(env)
+                        .unwrap_or_else(|e| (atoms::error(), error_reason(env, e)).encode(env)),
+                    (None, Some(e)) => (atoms::error(), error_reason(env, e)).encode(env),
+                    (None, None) => atoms::timeout().encode(env),
+                }
+            });
+            if let (false, Some(popped)) = (sent, popped) {
+                let _ = popped.restore(&waiting, &waiting_collection);
+            }
+        }
+        waiting
+            .waiters
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&waiting_key);
+        // Whatever was popped has been sent or put back by now, so a failure to drop the emptied
+        // tree changes nothing for the caller.
+        let _ = waiting.unwatch_tree(prefix, &waiting_collection);
+    });
+
+    if spawned.is_err() {
+        dbr.waiters
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&waiter);
+        let _ = dbr.unwatch_tree(prefix, &collection);
+        return Err(rustler::Error::Term(Box::new(atoms::system_limit())));
+    }
 
-    let mut iter = iter.filter_map(|l| l.ok()).take(limit);
+    Ok(atoms::ok())
+}
 
-    let mut removed: u64 = 0;
-    while let Some(k) = iter.next() {
-        let score_key_bytes = k[score_byte_len..]
-            .to_vec()
-            .into_iter()
-            .chain(SCORE_SUFFIX.iter().copied())
-            .collect::<Vec<_>>();
-        let value_key_bytes = k[score_byte_len..]
-            .to_vec()
-            .into_iter()
-            .chain(VALUE_SUFFIX.iter().copied())
-            .collect::<Vec<_>>();
+// Stops the blocking pop waiting under `reference`. When it already popped or timed out, its
+// message is in the caller's mailbox by the time this returns.
+#[rustler::nif(schedule = "DirtyIo")]
+fn cancel_blocking<'a>(
+    db_resouce: rustler::Term<'a>,
+    reference: rustler::Term<'a>,
+) -> NifResult<rustler::Atom> {
+    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
+    let waiter = dbr
+        .waiters
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(reference.to_binary().as_slice());
+    if let Some(cancelled) = waiter {
+        *cancelled.lock().unwrap_or_else(|e| e.into_inner()) = true;
+    }
+    Ok(atoms::ok())
+}
 
-        let ksvec = IVec::from(score_key_bytes);
-        let kvvec = IVec::from(value_key_bytes);
+#[rustler::nif(schedule = "DirtyIo")]
+fn blpop<'a>(
+    env: rustler::Env<'a>,
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    timeout: u64,
+    reference: rustler::Term<'a>,
+) -> NifResult<rustler::Atom> {
+    blocking_pop(
+        env,
+        db_resouce,
+        LIST_PREFIX,
+        collection,
+        timeout,
+        reference,
+        |dbr, collection| Ok(pop_list(dbr, collection, false)?.map(|(k, v)| Popped::Element(k, v))),
+    )
+}
 
-        key_tree.remove(ksvec).map_err(sled_err_into)?;
-        key_tree.remove(kvvec).map_err(sled_err_into)?;
-        score_tree.remove(k).map_err(sled_err_into)?;
-        removed += 1;
-    }
+#[rustler::nif(schedule = "DirtyIo")]
+fn brpop<'a>(
+    env: rustler::Env<'a>,
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    timeout: u64,
+    reference: rustler::Term<'a>,
+) -> NifResult<rustler::Atom> {
+    blocking_pop(
+        env,
+        db_resouce,
+        LIST_PREFIX,
+        collection,
+        timeout,
+        reference,
+        |dbr, collection| Ok(pop_list(dbr, collection, true)?.map(|(k, v)| Popped::Element(k, v))),
+    )
+}
 
-    if key_tree.is_empty() {
-        db.drop_tree(key_tree_bytes).map_err(sled_err_into)?;
-    }
-    if score_tree.is_empty() {
-        db.drop_tree(score_tree_bytes).map_err(sled_err_into)?;
-    }
+#[rustler::nif(schedule = "DirtyIo")]
+fn bzpopmin<'a>(
+    env: rustler::Env<'a>,
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    timeout: u64,
+    max_score: rustler::Term<'a>,
+    reference: rustler::Term<'a>,
+) -> NifResult<rustler::Atom> {
+    let score_type = collection_score_type(db_resouce, &collection)?;
+    let range = ScoreRange {
+        min: Bound::Unbounded,
+        max: decode_score_bound(score_type, max_score, false)?,
+    };
+    blocking_pop(
+        env,
+        db_resouce,
+        SCORE_PREFIX,
+        collection,
+        timeout,
+        reference,
+        move |dbr, collection| {
+            let popped = pop_members(dbr, collection, 1, range, false)?;
+            Ok(popped
+                .into_iter()
+                .next()
+                .map(|(index_key, value)| Popped::Member(index_key, value, score_type)))
+        },
+    )
+}
 
-    Ok(removed)
+#[rustler::nif(schedule = "DirtyIo")]
+fn lpop<'a>(
+    env: rustler::Env<'a>,
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+) -> NifResult<Option<rustler::Binary<'a>>> {
+    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
+
+    match pop_list(&dbr, &collection, false)? {
+        Some((_, elem)) => Ok(Some(make_binary(env, &elem)?)),
+        _ => Ok(None),
+    }
 }
 
-#[rustler::nif]
-fn zitercollectionrembyrangebyscore<'a>(
+#[rustler::nif(schedule = "DirtyIo")]
+fn rpop<'a>(
+    env: rustler::Env<'a>,
     db_resouce: rustler::Term<'a>,
     collection: rustler::Binary,
-    min_score: u64,
-    max_score: Option<u64>,
-    limit: usize,
-) -> NifResult<u64> {
+) -> NifResult<Option<rustler::Binary<'a>>> {
     let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
-    let db = &dbr.db;
-    let score_tree_bytes = SCORE_PREFIX
-        .to_vec()
-        .into_iter()
-        .chain(collection.as_slice().iter().copied())
-        .collect::<Vec<_>>();
-    let key_tree_bytes = KEY_PREFIX
-        .to_vec()
-        .into_iter()
-        .chain(collection.as_slice().iter().copied())
-        .collect::<Vec<_>>();
 
-    let score_tree: sled::Tree = db.open_tree(IVec::from(score_tree_bytes.clone())).unwrap();
-    let key_tree: sled::Tree = db.open_tree(IVec::from(key_tree_bytes.clone())).unwrap();
+    match pop_list(&dbr, &collection, true)? {
+        Some((_, elem)) => Ok(Some(make_binary(env, &elem)?)),
+        _ => Ok(None),
+    }
+}
 
-    let min_bytes = min_score.to_be_bytes().to_vec();
-    let score_byte_len = min_bytes.clone().len();
+fn list_len(list_tree: &sled::Tree) -> NifResult<u64> {
+    match list_tree.get(CARD_KEY).map_err(sled_err_into)? {
+        Some(card) => Ok(make_u64(&card)?),
+        None => Ok(0),
+    }
+}
 
-    let iter = if let Some(o) = max_score {
-        score_tree
-            .range(IVec::from(min_bytes)..IVec::from(o.to_be_bytes().to_vec()))
-            .keys()
+// Resolves inclusive `start` and `stop` indices, where negative indices count from the end of
+// the list, into the number of elements to skip and to take.
+fn list_span(len: u64, start: i64, stop: i64) -> Option<(u64, u64)> {
+    let len = len.min(i64::MAX as u64) as i64;
+    let start = if start < 0 {
+        (start + len).max(0)
     } else {
-        score_tree.range(IVec::from(min_bytes)..).keys()
+        start
     };
+    let stop = if stop < 0 {
+        stop + len
+    } else {
+        stop.min(len - 1)
+    };
+    if start > stop {
+        return None;
+    }
+    Some((start as u64, (stop - start + 1) as u64))
+}
 
-    let mut iter = iter.filter_map(|l| l.ok()).take(limit);
-
-    let mut removed: u64 = 0;
-    while let Some(k) = iter.next() {
-        let score_key_bytes = k[score_byte_len..]
-            .to_vec()
-            .into_iter()
-            .chain(SCORE_SUFFIX.iter().copied())
-            .collect::<Vec<_>>();
-        let value_key_bytes = k[score_byte_len..]
-            .to_vec()
-            .into_iter()
-            .chain(VALUE_SUFFIX.iter().copied())
-            .collect::<Vec<_>>();
-
-        let ksvec = IVec::from(score_key_bytes);
-        let kvvec = IVec::from(value_key_bytes);
+// Reads the elements in `start..=stop`, walking from whichever end of the list is closer.
+fn list_range(list_tree: &sled::Tree, start: i64, stop: i64) -> NifResult<Vec<IVec>> {
+    let len = list_len(list_tree)?;
+    let (skip, take) = match list_span(len, start, stop) {
+        Some(span) => span,
+        None => return Ok(vec![]),
+    };
 
-        key_tree.remove(ksvec).map_err(sled_err_into)?;
-        key_tree.remove(kvvec).map_err(sled_err_into)?;
-        score_tree.remove(k).map_err(sled_err_into)?;
-        removed += 1;
+    let elements = list_elements(list_tree).values();
+    if skip > len / 2 {
+        let mut range = elements
+            .rev()
+            .skip((len - skip - take) as usize)
+            .take(take as usize)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(sled_err_into)?;
+        range.reverse();
+        Ok(range)
+    } else {
+        elements
+            .skip(skip as usize)
+            .take(take as usize)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(sled_err_into)
     }
+}
 
-    if key_tree.is_empty() {
-        db.drop_tree(key_tree_bytes).map_err(sled_err_into)?;
-    }
-    if score_tree.is_empty() {
-        db.drop_tree(score_tree_bytes).map_err(sled_err_into)?;
+fn remove_list_keys(list_tree: &sled::Tree, keys: &[IVec]) -> NifResult<u64> {
+    let mut removed = 0;
+    for chunk in keys.chunks(REMOVE_CHUNK_SIZE) {
+        removed += list_tree
+            .transaction(|list_tree| {
+                let mut removed: u64 = 0;
+                for k in chunk {
+                    if list_tree.remove(k)?.is_some() {
+                        removed += 1;
+                    }
+                }
+                adjust_card(list_tree, -(removed as i64))?;
+                Ok(removed)
+            })
+            .map_err(transaction_err_into)?;
     }
-
     Ok(removed)
 }
 
 #[rustler::nif]
-fn zrangebyscore<'a>(
+fn llen<'a>(db_resouce: rustler::Term<'a>, collection: rustler::Binary) -> NifResult<u64> {
+    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
+
+    match dbr.existing_tree(LIST_PREFIX, &collection)? {
+        Some(list_tree) => list_len(&list_tree),
+        None => Ok(0),
+    }
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn lrange<'a>(
     env: rustler::Env<'a>,
     db_resouce: rustler::Term<'a>,
     collection: rustler::Binary,
-    min_score: u64,
-    max_score: Option<u64>,
-    offset: usize,
-    limit: usize,
+    start: i64,
+    stop: i64,
 ) -> NifResult<Vec<rustler::Binary<'a>>> {
     let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
-    let db = &dbr.db;
-    let score_tree_bytes = SCORE_PREFIX
-        .to_vec()
-        .into_iter()
-        .chain(collection.as_slice().iter().copied())
-        .collect::<Vec<_>>();
-
-    let score_tree: sled::Tree = db.open_tree(IVec::from(score_tree_bytes)).unwrap();
 
-    let min_bytes = min_score.to_be_bytes().to_vec();
-    let score_byte_len = min_bytes.clone().len();
-    let iter = if let Some(o) = max_score {
-        score_tree
-            .range(IVec::from(min_bytes)..IVec::from(o.to_be_bytes().to_vec()))
-            .keys()
-    } else {
-        score_tree.range(IVec::from(min_bytes)..).keys()
+    let list_tree = match dbr.existing_tree(LIST_PREFIX, &collection)? {
+        Some(tree) => tree,
+        None => return Ok(vec![]),
     };
 
-    Ok(iter
-        .filter_map(|l| l.ok())
-        .skip(offset)
-        .take(limit)
-        .map(|result| make_binary(env, &result[score_byte_len..]))
-        .collect::<Vec<_>>())
+    list_range(&list_tree, start, stop)?
+        .iter()
+        .map(|elem| make_binary(env, elem))
+        .collect::<NifResult<Vec<_>>>()
 }
 
-#[rustler::nif]
-fn zrangebyprefixscore<'a>(
+#[rustler::nif(schedule = "DirtyIo")]
+fn lindex<'a>(
     env: rustler::Env<'a>,
     db_resouce: rustler::Term<'a>,
     collection: rustler::Binary,
-    prefix: rustler::Binary,
-    min_score: u64,
-    max_score: Option<u64>,
-    offset: usize,
-    limit: usize,
-) -> NifResult<Vec<rustler::Binary<'a>>> {
+    index: i64,
+) -> NifResult<Option<rustler::Binary<'a>>> {
     let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
-    let db = &dbr.db;
-    let key_tree_bytes = KEY_PREFIX
-        .to_vec()
-        .into_iter()
-        .chain(collection.as_slice().iter().copied())
-        .collect::<Vec<_>>();
 
-    let key_tree: sled::Tree = db.open_tree(IVec::from(key_tree_bytes.clone())).unwrap();
-
-    let iter = key_tree.scan_prefix(prefix.as_slice());
+    let list_tree = match dbr.existing_tree(LIST_PREFIX, &collection)? {
+        Some(tree) => tree,
+        None => return Ok(None),
+    };
 
-    Ok(iter
-        .filter_map(|l| l.ok())
-        .filter(|l| l.0.last() == SCORE_SUFFIX.last())
-        .filter(|result| {
-            let score = make_u64(&result.1);
-            if let Some(o) = max_score {
-                min_score <= score && score < o
-            } else {
-                min_score <= score
-            }
-        })
-        .skip(offset)
-        .take(limit)
-        .map(|result| make_binary(env, &result.0[..(result.0.len() - 1)]))
-        .collect::<Vec<_>>())
+    list_range(&list_tree, index, index)?
+        .first()
+        .map(|elem| make_binary(env, elem))
+        .transpose()
 }
 
-#[rustler::nif]
-fn zexists<'a>(
+fn peek_list<'a>(
+    env: rustler::Env<'a>,
     db_resouce: rustler::Term<'a>,
     collection: rustler::Binary,
-    min_score: u64,
-    max_score: Option<u64>,
-) -> NifResult<bool> {
+    rev: bool,
+) -> NifResult<Option<rustler::Binary<'a>>> {
     let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
-    let db = &dbr.db;
-    let score_tree_bytes = SCORE_PREFIX
-        .to_vec()
-        .into_iter()
-        .chain(collection.as_slice().iter().copied())
-        .collect::<Vec<_>>();
 
-    let score_tree: sled::Tree = db.open_tree(IVec::from(score_tree_bytes)).unwrap();
+    let list_tree = match dbr.existing_tree(LIST_PREFIX, &collection)? {
+        Some(tree) => tree,
+        None => return Ok(None),
+    };
 
-    let min_bytes = min_score.to_be_bytes().to_vec();
-    let iter = if let Some(o) = max_score {
-        score_tree
-            .range(IVec::from(min_bytes)..IVec::from(o.to_be_bytes().to_vec()))
-            .keys()
+    let mut elements = list_elements(&list_tree).values();
+    let next = if rev {
+        elements.next_back()
     } else {
-        score_tree.range(IVec::from(min_bytes)..).keys()
+        elements.next()
     };
-    Ok(!iter
-        .filter_map(|l| l.ok())
-        .take(1)
-        .collect::<Vec<_>>()
-        .is_empty())
+    match next {
+        Some(elem) => Ok(Some(make_binary(env, &elem.map_err(sled_err_into)?)?)),
+        None => Ok(None),
+    }
 }
 
 #[rustler::nif]
-fn zgetbykey<'a>(
+fn lpeek<'a>(
     env: rustler::Env<'a>,
     db_resouce: rustler::Term<'a>,
     collection: rustler::Binary,
-    key: rustler::Binary,
-    min_score: u64,
-) -> NifResult<Option<(Option<rustler::Binary<'a>>, Option<u64>)>> {
-    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
-    let db = &dbr.db;
-    let key_tree_bytes = KEY_PREFIX
-        .to_vec()
-        .into_iter()
-        .chain(collection.as_slice().iter().copied())
-        .collect::<Vec<_>>();
+) -> NifResult<Option<rustler::Binary<'a>>> {
+    peek_list(env, db_resouce, collection, false)
+}
 
-    let key_tree: sled::Tree = db.open_tree(IVec::from(key_tree_bytes)).unwrap();
+#[rustler::nif]
+fn rpeek<'a>(
+    env: rustler::Env<'a>,
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+) -> NifResult<Option<rustler::Binary<'a>>> {
+    peek_list(env, db_resouce, collection, true)
+}
 
-    let value_key_bytes = key
-        .as_slice()
-        .to_vec()
-        .into_iter()
-        .chain(VALUE_SUFFIX.iter().copied())
-        .collect::<Vec<_>>();
-    let score_key_bytes = key
-        .as_slice()
-        .to_vec()
-        .into_iter()
-        .chain(SCORE_SUFFIX.iter().copied())
-        .collect::<Vec<_>>();
+// The length and end keys of a list, which every push, pop and removal changes.
+fn list_snapshot(list_tree: &sled::Tree) -> NifResult<(u64, Option<IVec>, Option<IVec>)> {
+    let mut keys = list_elements(list_tree).keys();
+    let first = keys.next().transpose().map_err(sled_err_into)?;
+    let last = keys.next_back().transpose().map_err(sled_err_into)?;
+    Ok((list_len(list_tree)?, first, last))
+}
 
-    let ksvec = IVec::from(score_key_bytes);
-    let kvvec = IVec::from(value_key_bytes);
-    let value = key_tree.get(kvvec.clone()).map_err(sled_err_into)?;
-    let score = key_tree.get(ksvec.clone()).map_err(sled_err_into)?;
-    let score_dec = if let Some(s) = score {
-        Some(make_u64(&s))
-    } else {
-        None
+// Removes the keys picked by `select`. The list is scanned under the shared lock so pushes and
+// pops are not held up, and only rescanned under the exclusive lock if it changed meanwhile.
+fn remove_from_list<F>(dbr: &DbResource, collection: &[u8], select: F) -> NifResult<u64>
+where
+    F: Fn(&sled::Tree) -> NifResult<Vec<IVec>>,
+{
+    let (snapshot, keys) = {
+        let _guard = dbr.lock_collection(collection);
+        match dbr.existing_tree(LIST_PREFIX, collection)? {
+            Some(list_tree) => (list_snapshot(&list_tree)?, select(&list_tree)?),
+            None => return Ok(0),
+        }
     };
-    let value_dec = if let Some(v) = value {
-        Some(make_binary(env, &v))
-    } else {
-        None
+
+    let guard = dbr.lock_collection_exclusive(collection);
+    let list_tree = match dbr.existing_tree(LIST_PREFIX, collection)? {
+        Some(tree) => tree,
+        None => return Ok(0),
     };
-    if !value_dec.is_none() || !score_dec.is_none() {
-        if score_dec.unwrap_or(u64::MAX) >= min_score {
-            Ok(Some((value_dec, score_dec)))
-        } else {
-            Ok(None)
-        }
+    let keys = if list_snapshot(&list_tree)? == snapshot {
+        keys
     } else {
-        Ok(None)
+        select(&list_tree)?
+    };
+    let removed = remove_list_keys(&list_tree, &keys)?;
+
+    drop(guard);
+    if list_tree.is_empty() {
+        dbr.drop_tree_if_empty(LIST_PREFIX, collection)?;
     }
+    Ok(removed)
 }
 
-#[rustler::nif]
-fn zrem<'a>(
+#[rustler::nif(schedule = "DirtyIo")]
+fn ltrim<'a>(
     db_resouce: rustler::Term<'a>,
     collection: rustler::Binary,
-    key: rustler::Binary,
+    start: i64,
+    stop: i64,
 ) -> NifResult<rustler::Atom> {
     let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
-    let db = &dbr.db;
-    let key_tree_bytes = KEY_PREFIX
-        .to_vec()
-        .into_iter()
-        .chain(collection.as_slice().iter().copied())
-        .collect::<Vec<_>>();
-    let score_tree_bytes = SCORE_PREFIX
-        .to_vec()
-        .into_iter()
-        .chain(collection.as_slice().iter().copied())
-        .collect::<Vec<_>>();
-    let key_tree: sled::Tree = db.open_tree(IVec::from(key_tree_bytes.clone())).unwrap();
-    let score_tree: sled::Tree = db.open_tree(IVec::from(score_tree_bytes.clone())).unwrap();
 
-    let value_key_bytes = key
-        .as_slice()
-        .to_vec()
-        .into_iter()
-        .chain(VALUE_SUFFIX.iter().copied())
-        .collect::<Vec<_>>();
-    let score_key_bytes = key
-        .as_slice()
-        .to_vec()
-        .into_iter()
-        .chain(SCORE_SUFFIX.iter().copied())
-        .collect::<Vec<_>>();
+    remove_from_list(&dbr, &collection, |list_tree| {
+        let len = list_len(list_tree)?;
+        let (skip, take) = list_span(len, start, stop).unwrap_or((len, 0));
+        let head = list_elements(list_tree).keys().take(skip as usize);
+        let tail = list_elements(list_tree)
+            .keys()
+            .rev()
+            .take(len.saturating_sub(skip + take) as usize);
+        head.chain(tail)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(sled_err_into)
+    })?;
 
-    let ksvec = IVec::from(score_key_bytes);
-    let kvvec = IVec::from(value_key_bytes);
-    key_tree.remove(kvvec.clone()).map_err(sled_err_into)?;
-    let score = key_tree.get(ksvec.clone()).map_err(sled_err_into)?;
+    Ok(atoms::ok())
+}
 
-    if let Some(s) = score {
-        let score_bytes = s
-            .to_vec()
-            .into_iter()
-            .chain(key.as_slice().iter().copied())
-            .collect::<Vec<_>>();
-        score_tree.remove(score_bytes).map_err(sled_err_into)?;
+// Removes up to `count` elements equal to `value` starting from the head, or from the tail when
+// `count` is negative. A `count` of zero removes all of them.
+#[rustler::nif(schedule = "DirtyIo")]
+fn lrem<'a>(
+    db_resouce: rustler::Term<'a>,
+    collection: rustler::Binary,
+    value: rustler::Binary,
+    count: i64,
+) -> NifResult<u64> {
+    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
+    let limit = match count.unsigned_abs() {
+        0 => usize::MAX,
+        n => n as usize,
+    };
+
+    remove_from_list(&dbr, &collection, |list_tree| {
+        let elements = list_elements(list_tree);
+        let elements: Box<dyn Iterator<Item = sled::Result<(IVec, IVec)>>> = if count < 0 {
+            Box::new(elements.rev())
+        } else {
+            Box::new(elements)
+        };
+
+        let mut keys = Vec::new();
+        for result in elements {
+            let (k, v) = result.map_err(sled_err_into)?;
+            if v == value.as_slice() {
+                keys.push(k);
+                if keys.len() >= limit {
+                    break;
+                }
+            }
+        }
+        Ok(keys)
+    })
+}
+
+// An operation of a pipeline. All ops are decoded before any runs, so a malformed op fails the
+// whole call.
+enum PipelineOp<'a> {
+    ZAdd {
+        collection: rustler::Binary<'a>,
+        key: Operand<'a>,
+        value: Option<rustler::Binary<'a>>,
+        score: Option<rustler::Term<'a>>,
+        options: WriteOptions,
+    },
+    ZScoreUpdate {
+        collection: rustler::Binary<'a>,
+        key: Operand<'a>,
+        score: Option<rustler::Term<'a>>,
+        options: WriteOptions,
+    },
+    ZIncrBy {
+        collection: rustler::Binary<'a>,
+        key: Operand<'a>,
+        delta: rustler::Term<'a>,
+    },
+    ZRem {
+        collection: rustler::Binary<'a>,
+        key: Operand<'a>,
+    },
+    ZScore {
+        collection: rustler::Binary<'a>,
+        key: Operand<'a>,
+    },
+    ZGetByKey {
+        collection: rustler::Binary<'a>,
+        key: Operand<'a>,
+        min_score: rustler::Term<'a>,
+    },
+    Push {
+        collection: rustler::Binary<'a>,
+        value: Operand<'a>,
+        left: bool,
+    },
+    Pop {
+        collection: rustler::Binary<'a>,
+        left: bool,
+    },
+}
+
+impl<'a> PipelineOp<'a> {
+    // Ops are tuples named after the function they stand for, taking the same arguments
+    // without the database. Write options may be left out.
+    fn decode(term: rustler::Term<'a>, earlier: &[PipelineOp]) -> NifResult<Self> {
+        let tuple = rustler::types::tuple::get_tuple(term)?;
+        let (name, args) = tuple.split_first().ok_or(rustler::Error::BadArg)?;
+        let name: rustler::Atom = name.decode()?;
+        let options = |i: usize| match args.get(i) {
+            Some(options) => WriteOptions::decode(*options),
+            None => Ok(WriteOptions::default()),
+        };
+        let operand = |i: usize| Operand::decode(args[i], earlier);
+
+        let op = if name == atoms::zadd() && (args.len() == 4 || args.len() == 5) {
+            PipelineOp::ZAdd {
+                collection: args[0].decode()?,
+                key: operand(1)?,
+                value: args[2].decode()?,
+                score: args[3].decode()?,
+                options: options(4)?,
+            }
+        } else if name == atoms::zscoreupdate() && (args.len() == 3 || args.len() == 4) {
+            PipelineOp::ZScoreUpdate {
+                collection: args[0].decode()?,
+                key: operand(1)?,
+                score: args[2].decode()?,
+                options: options(3)?,
+            }
+        } else if name == atoms::zincrby() && args.len() == 3 {
+            PipelineOp::ZIncrBy {
+                collection: args[0].decode()?,
+                key: operand(1)?,
+                delta: args[2].decode()?,
+            }
+        } else if name == atoms::zrem() && args.len() == 2 {
+            PipelineOp::ZRem {
+                collection: args[0].decode()?,
+                key: operand(1)?,
+            }
+        } else if name == atoms::zscore() && args.len() == 2 {
+            PipelineOp::ZScore {
+                collection: args[0].decode()?,
+                key: operand(1)?,
+            }
+        } else if name == atoms::zgetbykey() && args.len() == 3 {
+            PipelineOp::ZGetByKey {
+                collection: args[0].decode()?,
+                key: operand(1)?,
+                min_score: args[2].decode()?,
+            }
+        } else if (name == atoms::lpush() || name == atoms::rpush()) && args.len() == 2 {
+            PipelineOp::Push {
+                collection: args[0].decode()?,
+                value: operand(1)?,
+                left: name == atoms::lpush(),
+            }
+        } else if (name == atoms::lpop() || name == atoms::rpop()) && args.len() == 1 {
+            PipelineOp::Pop {
+                collection: args[0].decode()?,
+                left: name == atoms::lpop(),
+            }
+        } else {
+            return Err(rustler::Error::BadArg);
+        };
+        Ok(op)
     }
 
-    key_tree.remove(ksvec.clone()).map_err(sled_err_into)?;
+    fn collection(&self) -> &[u8] {
+        match self {
+            PipelineOp::ZAdd { collection, .. }
+            | PipelineOp::ZScoreUpdate { collection, .. }
+            | PipelineOp::ZIncrBy { collection, .. }
+            | PipelineOp::ZRem { collection, .. }
+            | PipelineOp::ZScore { collection, .. }
+            | PipelineOp::ZGetByKey { collection, .. }
+            | PipelineOp::Push { collection, .. }
+            | PipelineOp::Pop { collection, .. } => collection.as_slice(),
+        }
+    }
 
-    if key_tree.is_empty() {
-        db.drop_tree(key_tree_bytes).map_err(sled_err_into)?;
+    // The trees an op works on, in the order `apply` expects them.
+    fn prefixes(&self) -> &'static [&'static [u8]] {
+        match self {
+            PipelineOp::ZScore { .. } | PipelineOp::ZGetByKey { .. } => &[KEY_PREFIX],
+            PipelineOp::Push { .. } | PipelineOp::Pop { .. } => &[LIST_PREFIX],
+            _ => &[SCORE_PREFIX, KEY_PREFIX],
+        }
     }
-    if score_tree.is_empty() {
-        db.drop_tree(score_tree_bytes).map_err(sled_err_into)?;
+
+    // Reads and pops leave missing collections missing.
+    fn creates_trees(&self) -> bool {
+        !matches!(
+            self,
+            PipelineOp::ZScore { .. } | PipelineOp::ZGetByKey { .. } | PipelineOp::Pop { .. }
+        )
     }
 
-    Ok(atoms::ok())
+    // Pops read the list before they write, so they need the collection to themselves.
+    fn pops(&self) -> bool {
+        matches!(self, PipelineOp::Pop { .. })
+    }
+
+    // The key or pushed value of an op.
+    fn operand(&self) -> Option<&Operand<'a>> {
+        match self {
+            PipelineOp::ZAdd { key, .. }
+            | PipelineOp::ZScoreUpdate { key, .. }
+            | PipelineOp::ZIncrBy { key, .. }
+            | PipelineOp::ZRem { key, .. }
+            | PipelineOp::ZScore { key, .. }
+            | PipelineOp::ZGetByKey { key, .. } => Some(key),
+            PipelineOp::Push { value, .. } => Some(value),
+            PipelineOp::Pop { .. } => None,
+        }
+    }
+
+    // Trees are `None` when a read or pop op's collection does not exist. `popped` holds what
+    // earlier pops returned and `lists` the list changes of earlier ops in the same transaction.
+    fn apply(
+        &self,
+        score_type: ScoreType,
+        trees: &[Option<(&sled::Tree, &TransactionalTree)>],
+        popped: &[Option<IVec>],
+        lists: &mut HashMap<IVec, ListChanges>,
+    ) -> ConflictableTransactionResult<PipelineResult, rustler::error::Error> {
+        // Ops on the value of a pop that found its list empty do nothing.
+        let operand = match self.operand().map(|operand| operand.resolve(popped)) {
+            Some(Some(operand)) => operand,
+            Some(None) => return Ok(PipelineResult::Value(None)),
+            None => &[],
+        };
+        let decode_score = |score: &Option<rustler::Term>| {
+            score
+                .map(|s| score_type.decode(s))
+                .transpose()
+                .map_err(ConflictableTransactionError::Abort)
+        };
+        let result = match (self, trees) {
+            (
+                PipelineOp::ZAdd {
+                    value,
+                    score,
+                    options,
+                    ..
+                },
+                [Some((_, score_tree)), Some((_, key_tree))],
+            ) => {
+                let value = value.as_ref().map(|v| v.as_slice());
+                let result = write_member(
+                    score_tree,
+                    key_tree,
+                    operand,
+                    Some(value),
+                    decode_score(score)?,
+                    true,
+                    options,
+                )?;
+                PipelineResult::Write(result, *options)
+            }
+            (
+                PipelineOp::ZScoreUpdate { score, options, .. },
+                [Some((_, score_tree)), Some((_, key_tree))],
+            ) => {
+                let result = write_member(
+                    score_tree,
+                    key_tree,
+                    operand,
+                    None,
+                    decode_score(score)?,
+                    false,
+                    options,
+                )?;
+                PipelineResult::Write(result, *options)
+            }
+            (PipelineOp::ZIncrBy { delta, .. }, [Some((_, score_tree)), Some((_, key_tree))]) => {
+                PipelineResult::Score(incr_member(
+                    score_tree, key_tree, operand, score_type, *delta,
+                )?)
+            }
+            (PipelineOp::ZRem { .. }, [Some((_, score_tree)), Some((_, key_tree))]) => {
+                delete_member(score_tree, key_tree, operand)?;
+                PipelineResult::Ok
+            }
+            (PipelineOp::ZScore { .. }, [key_tree]) => {
+                let (value, score) = read_member(key_tree.map(|(_, view)| view), operand)?;
+                PipelineResult::Exists(value.is_some() || score.is_some(), score)
+            }
+            (PipelineOp::ZGetByKey { min_score, .. }, [key_tree]) => {
+                let (value, score) = read_member(key_tree.map(|(_, view)| view), operand)?;
+                let min_score = score_type
+                    .decode(*min_score)
+                    .map_err(ConflictableTransactionError::Abort)?;
+                PipelineResult::Entry(value, score, min_score)
+            }
+            (PipelineOp::Push { left, .. }, [Some((tree, list_tree))]) => {
+                let key = push_list(list_tree, operand, *left)?;
+                let changes = lists.entry(tree.name()).or_default();
+                changes.pushed.insert(key, IVec::from(operand));
+                PipelineResult::Ok
+            }
+            (PipelineOp::Pop { left, .. }, [Some((tree, list_tree))]) => {
+                let changes = lists.entry(tree.name()).or_default();
+                PipelineResult::Value(changes.pop(tree, list_tree, *left)?)
+            }
+            (PipelineOp::Pop { .. }, [None]) => PipelineResult::Value(None),
+            _ => return Err(ConflictableTransactionError::Abort(rustler::Error::BadArg)),
+        };
+        Ok(result)
+    }
 }
 
-#[rustler::nif]
-fn rpush<'a>(
-    db_resouce: rustler::Term<'a>,
-    collection: rustler::Binary,
-    value: rustler::Binary,
-) -> NifResult<rustler::Atom> {
-    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
-    let db = &dbr.db;
-    let list_tree_bytes = LIST_PREFIX
-        .to_vec()
-        .into_iter()
-        .chain(collection.as_slice().iter().copied())
-        .collect::<Vec<_>>();
+// A key or value given in an op, or `{:popped, index}` for the value returned by an earlier pop
+// of the same pipeline.
+enum Operand<'a> {
+    Bytes(rustler::Binary<'a>),
+    Popped(usize),
+}
 
-    let list_tree: sled::Tree = db.open_tree(IVec::from(list_tree_bytes)).unwrap();
-    let right_side_id = db.generate_id().map_err(sled_err_into)?;
+impl<'a> Operand<'a> {
+    fn decode(term: rustler::Term<'a>, earlier: &[PipelineOp]) -> NifResult<Self> {
+        if let Ok(bytes) = term.decode() {
+            return Ok(Operand::Bytes(bytes));
+        }
+        let (tag, index): (rustler::Atom, usize) = term.decode()?;
+        match earlier.get(index) {
+            Some(PipelineOp::Pop { .. }) if tag == atoms::popped() => Ok(Operand::Popped(index)),
+            _ => Err(rustler::Error::BadArg),
+        }
+    }
 
-    let list_key = ((right_side_id as i128 - i64::MAX as i128) as i64)
-        .to_be_bytes()
-        .to_vec();
+    fn resolve<'b>(&'b self, popped: &'b [Option<IVec>]) -> Option<&'b [u8]> {
+        match self {
+            Operand::Bytes(bytes) => Some(bytes.as_slice()),
+            Operand::Popped(index) => popped.get(*index)?.as_deref(),
+        }
+    }
+}
 
-    list_tree
-        .insert(list_key, value.as_slice())
-        .map_err(sled_err_into)?;
+// Elements pushed and popped by earlier ops of a transaction, which the transactional view of a
+// list cannot iterate. The committed list stays put meanwhile as pops lock it exclusively.
+#[derive(Default)]
+struct ListChanges {
+    pushed: BTreeMap<[u8; 8], IVec>,
+    popped: HashSet<IVec>,
+}
 
-    Ok(atoms::ok())
+impl ListChanges {
+    fn pop(
+        &mut self,
+        committed: &sled::Tree,
+        list_tree: &TransactionalTree,
+        left: bool,
+    ) -> ConflictableTransactionResult<Option<IVec>, rustler::error::Error> {
+        let mut elements = list_elements(committed);
+        let next_committed = loop {
+            let next = if left {
+                elements.next()
+            } else {
+                elements.next_back()
+            };
+            match next.transpose()? {
+                Some((key, _)) if self.popped.contains(&key) => continue,
+                next => break next,
+            }
+        };
+        let next_pushed = if left {
+            self.pushed.iter().next()
+        } else {
+            self.pushed.iter().next_back()
+        }
+        .map(|(key, value)| (*key, value.clone()));
+
+        let take_pushed = match (&next_committed, &next_pushed) {
+            (_, None) => false,
+            (None, Some(_)) => true,
+            (Some((committed, _)), Some((pushed, _))) => (pushed[..] < committed[..]) == left,
+        };
+        let (key, value) = match (next_committed, next_pushed) {
+            (_, Some((key, value))) if take_pushed => {
+                self.pushed.remove(&key);
+                (IVec::from(&key[..]), value)
+            }
+            (Some((key, value)), _) => {
+                self.popped.insert(key.clone());
+                (key, value)
+            }
+            _ => return Ok(None),
+        };
+        list_tree.remove(key)?;
+        adjust_card(list_tree, -1)?;
+        Ok(Some(value))
+    }
 }
 
-#[rustler::nif]
-fn lpush<'a>(
-    db_resouce: rustler::Term<'a>,
-    collection: rustler::Binary,
-    value: rustler::Binary,
-) -> NifResult<rustler::Atom> {
-    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
-    let db = &dbr.db;
-    let list_tree_bytes = LIST_PREFIX
-        .to_vec()
-        .into_iter()
-        .chain(collection.as_slice().iter().copied())
+fn read_member(
+    key_tree: Option<&TransactionalTree>,
+    key: &[u8],
+) -> ConflictableTransactionResult<(Option<IVec>, Option<IVec>), rustler::error::Error> {
+    let key_tree = match key_tree {
+        Some(tree) => tree,
+        None => return Ok((None, None)),
+    };
+    let value_key_bytes = key
+        .iter()
+        .chain(VALUE_SUFFIX.iter())
+        .copied()
+        .collect::<Vec<_>>();
+    let score_key_bytes = key
+        .iter()
+        .chain(SCORE_SUFFIX.iter())
+        .copied()
         .collect::<Vec<_>>();
+    Ok((
+        key_tree.get(value_key_bytes)?,
+        key_tree.get(score_key_bytes)?,
+    ))
+}
 
-    let list_tree: sled::Tree = db.open_tree(IVec::from(list_tree_bytes)).unwrap();
-    let right_side_id = db.generate_id().map_err(sled_err_into)?;
-    let key = -1 * ((right_side_id as i128 - i64::MAX as i128) as i64);
+// Results are read inside the transaction and only turned into terms once it commits.
+enum PipelineResult {
+    Ok,
+    Value(Option<IVec>),
+    Write(WriteResult, WriteOptions),
+    Score(u64),
+    Exists(bool, Option<IVec>),
+    Entry(Option<IVec>, Option<IVec>, u64),
+}
 
-    let list_key = key.to_be_bytes().to_vec();
+impl PipelineResult {
+    fn popped(&self) -> Option<IVec> {
+        match self {
+            PipelineResult::Value(value) => value.clone(),
+            _ => None,
+        }
+    }
 
-    list_tree
-        .insert(list_key, value.as_slice())
-        .map_err(sled_err_into)?;
+    fn encode<'a>(
+        self,
+        env: rustler::Env<'a>,
+        score_type: ScoreType,
+    ) -> NifResult<rustler::Term<'a>> {
+        match self {
+            PipelineResult::Ok => Ok(atoms::ok().encode(env)),
+            PipelineResult::Value(value) => {
+                Ok(value.map(|v| make_binary(env, &v)).transpose()?.encode(env))
+            }
+            PipelineResult::Write(result, options) => result.encode(env, &options, score_type),
+            PipelineResult::Score(score) => Ok(score_type.encode(env, score)),
+            PipelineResult::Exists(exists, score) => {
+                let score = make_score(env, score.as_deref(), score_type)?;
+                Ok((exists, score).encode(env))
+            }
+            PipelineResult::Entry(value, score, min_score) => {
+                let score_dec = score.as_ref().map(|s| make_u64(s)).transpose()?;
+                if score_dec.unwrap_or(u64::MAX) >= min_score {
+                    Ok(make_entry(env, value, score, score_type)?.encode(env))
+                } else {
+                    Ok(None::<u64>.encode(env))
+                }
+            }
+        }
+    }
+}
 
-    Ok(atoms::ok())
+// Tree handles shared by the ops of a pipeline, each opened once.
+#[derive(Default)]
+struct PipelineTrees {
+    trees: Vec<sled::Tree>,
+    names: Vec<(&'static [u8], Vec<u8>)>,
+    index: HashMap<Vec<u8>, usize>,
 }
 
-#[rustler::nif]
-fn lpop<'a>(
+impl PipelineTrees {
+    // Returns the positions of an op's trees. Reads do not create missing trees.
+    fn resolve(&mut self, dbr: &DbResource, op: &PipelineOp) -> NifResult<Vec<Option<usize>>> {
+        let collection = op.collection();
+        let mut slots = Vec::new();
+        for prefix in op.prefixes() {
+            let name = tree_name(prefix, collection);
+            if let Some(&i) = self.index.get(&name) {
+                slots.push(Some(i));
+                continue;
+            }
+            let tree = if op.creates_trees() {
+                Some(dbr.tree(prefix, collection)?)
+            } else {
+                dbr.existing_tree(prefix, collection)?
+            };
+            slots.push(tree.map(|tree| {
+                self.trees.push(tree);
+                self.names.push((prefix, collection.to_vec()));
+                self.index.insert(name, self.trees.len() - 1);
+                self.trees.len() - 1
+            }));
+        }
+        Ok(slots)
+    }
+}
+
+// sled cannot run a transaction over no trees, which happens when every op only reads
+// collections that do not exist.
+fn transact<T, F>(trees: &[sled::Tree], f: F) -> NifResult<T>
+where
+    F: Fn(&[TransactionalTree]) -> ConflictableTransactionResult<T, rustler::error::Error>,
+{
+    if trees.is_empty() {
+        return f(&[]).map_err(|e| match e {
+            ConflictableTransactionError::Abort(e) => e,
+            ConflictableTransactionError::Storage(e) => sled_err_into(e),
+            // Nothing can conflict without trees, so this would be a bug in an op.
+            ConflictableTransactionError::Conflict => sled_err_into(sled::Error::ReportableBug(
+                "transaction conflict without trees".to_string(),
+            )),
+        });
+    }
+    trees
+        .transaction(|views| f(views))
+        .map_err(transaction_err_into)
+}
+
+fn run_pipeline<'a>(
     env: rustler::Env<'a>,
     db_resouce: rustler::Term<'a>,
-    collection: rustler::Binary,
-) -> NifResult<Option<rustler::Binary<'a>>> {
+    ops: Vec<rustler::Term<'a>>,
+    atomic: bool,
+) -> NifResult<Vec<rustler::Term<'a>>> {
     let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
-    let db = &dbr.db;
-    let list_tree_bytes = LIST_PREFIX
-        .to_vec()
-        .into_iter()
-        .chain(collection.as_slice().iter().copied())
-        .collect::<Vec<_>>();
-
-    let list_tree: sled::Tree = db.open_tree(IVec::from(list_tree_bytes.clone())).unwrap();
+    let mut decoded = Vec::with_capacity(ops.len());
+    for op in ops {
+        let op = PipelineOp::decode(op, &decoded)?;
+        decoded.push(op);
+    }
+    let ops = decoded;
+
+    let guards = dbr.lock_collections(ops.iter().map(|op| (op.collection(), op.pops())));
+    let mut trees = PipelineTrees::default();
+    let slots = ops
+        .iter()
+        .map(|op| trees.resolve(&dbr, op))
+        .collect::<NifResult<Vec<_>>>()?;
+    let mut collection_types = HashMap::new();
+    let score_types = ops
+        .iter()
+        .map(|op| match collection_types.get(op.collection()) {
+            Some(&score_type) => Ok(score_type),
+            None => {
+                let score_type = dbr.score_type(op.collection())?;
+                collection_types.insert(op.collection(), score_type);
+                Ok(score_type)
+            }
+        })
+        .collect::<NifResult<Vec<_>>>()?;
+
+    let results = if atomic {
+        let results = transact(&trees.trees, |views| {
+            let mut lists = HashMap::new();
+            let mut popped = Vec::with_capacity(ops.len());
+            let mut results = Vec::with_capacity(ops.len());
+            for ((op, slots), &score_type) in ops.iter().zip(&slots).zip(&score_types) {
+                let op_trees = slots
+                    .iter()
+                    .map(|slot| slot.map(|i| (&trees.trees[i], &views[i])))
+                    .collect::<Vec<_>>();
+                let result = op.apply(score_type, &op_trees, &popped, &mut lists)?;
+                popped.push(result.popped());
+                results.push(result);
+            }
+            Ok(results)
+        })?;
+        results
+            .into_iter()
+            .zip(score_types)
+            .map(|(result, score_type)| result.encode(env, score_type))
+            .collect::<NifResult<Vec<_>>>()?
+    } else {
+        // A failed pop counts as having found its list empty for the ops using its value.
+        let mut popped = Vec::with_capacity(ops.len());
+        let mut results = Vec::with_capacity(ops.len());
+        for ((op, slots), &score_type) in ops.iter().zip(&slots).zip(&score_types) {
+            let op_trees = slots
+                .iter()
+                .flatten()
+                .map(|&i| trees.trees[i].clone())
+                .collect::<Vec<_>>();
+            let result = transact(&op_trees, |views| {
+                let mut views = op_trees.iter().zip(views);
+                let op_trees = slots
+                    .iter()
+                    .map(|slot| slot.and_then(|_| views.next()))
+                    .collect::<Vec<_>>();
+                op.apply(score_type, &op_trees, &popped, &mut HashMap::new())
+            });
+            popped.push(result.as_ref().ok().and_then(PipelineResult::popped));
+            results.push(
+                match result.and_then(|result| result.encode(env, score_type)) {
+                    Ok(term) => term,
+                    Err(e) => (atoms::error(), error_reason(env, e)).encode(env),
+                },
+            );
+        }
+        results
+    };
 
-    let maybe_left = list_tree.pop_min().map_err(sled_err_into)?;
-    if list_tree.is_empty() {
-        db.drop_tree(list_tree_bytes).map_err(sled_err_into)?;
+    drop(guards);
+    for (tree, (prefix, collection)) in trees.trees.iter().zip(&trees.names) {
+        if tree.is_empty() {
+            dbr.drop_tree_if_empty(prefix, collection)?;
+        }
     }
-    match maybe_left {
-        Some(elem) => Ok(Some(make_binary(env, &elem.1))),
-        _ => Ok(None),
+    Ok(results)
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn pipeline<'a>(
+    env: rustler::Env<'a>,
+    db_resouce: rustler::Term<'a>,
+    ops: Vec<rustler::Term<'a>>,
+    opts: rustler::Term<'a>,
+) -> NifResult<Vec<rustler::Term<'a>>> {
+    let mut atomic = false;
+    for (k, v) in decode_options(opts)? {
+        if k == atoms::atomic() {
+            atomic = v.decode()?;
+        } else {
+            return Err(rustler::Error::BadArg);
+        }
     }
+    run_pipeline(env, db_resouce, ops, atomic)
 }
 
-#[rustler::nif]
-fn rpop<'a>(
+// A pipeline that always runs atomically.
+#[rustler::nif(schedule = "DirtyIo")]
+fn transaction<'a>(
     env: rustler::Env<'a>,
     db_resouce: rustler::Term<'a>,
-    collection: rustler::Binary,
-) -> NifResult<Option<rustler::Binary<'a>>> {
-    let dbr: rustler::ResourceArc<DbResource> = db_resouce.decode()?;
-    let db = &dbr.db;
-    let list_tree_bytes = LIST_PREFIX
-        .to_vec()
-        .into_iter()
-        .chain(collection.as_slice().iter().copied())
-        .collect::<Vec<_>>();
+    ops: Vec<rustler::Term<'a>>,
+) -> NifResult<Vec<rustler::Term<'a>>> {
+    run_pipeline(env, db_resouce, ops, true)
+}
 
-    let list_tree: sled::Tree = db.open_tree(IVec::from(list_tree_bytes.clone())).unwrap();
+fn make_binary<'a>(env: rustler::Env<'a>, bytes: &[u8]) -> NifResult<rustler::Binary<'a>> {
+    let mut bin = rustler::OwnedBinary::new(bytes.len())
+        .ok_or_else(|| rustler::error::Error::Term(Box::new(atoms::out_of_memory())))?;
+    bin.as_mut_slice().copy_from_slice(bytes);
 
-    let maybe_left = list_tree.pop_max().map_err(sled_err_into)?;
-    if list_tree.is_empty() {
-        db.drop_tree(list_tree_bytes).map_err(sled_err_into)?;
-    }
-    match maybe_left {
-        Some(elem) => Ok(Some(make_binary(env, &elem.1))),
-        _ => Ok(None),
+    Ok(bin.release(env))
+}
+
+// `{value, score}` of a member, or nil when it does not exist.
+type Entry<'a> = Option<(Option<rustler::Binary<'a>>, Option<rustler::Term<'a>>)>;
+
+fn make_entry<'a>(
+    env: rustler::Env<'a>,
+    value: Option<IVec>,
+    score: Option<IVec>,
+    score_type: ScoreType,
+) -> NifResult<Entry<'a>> {
+    if value.is_none() && score.is_none() {
+        return Ok(None);
     }
+    let value = value.map(|v| make_binary(env, &v)).transpose()?;
+    let score = make_score(env, score.as_deref(), score_type)?;
+    Ok(Some((value, score)))
 }
 
-fn make_binary<'a>(env: rustler::Env<'a>, bytes: &[u8]) -> rustler::Binary<'a> {
-    let mut bin = match rustler::OwnedBinary::new(bytes.len()) {
-        Some(bin) => bin,
-        None => panic!("binary term allocation fail"),
-    };
-    bin.as_mut_slice()
-        .write_all(&bytes[..])
-        .expect("memory copy of string failed");
+fn make_score<'a>(
+    env: rustler::Env<'a>,
+    score: Option<&[u8]>,
+    score_type: ScoreType,
+) -> NifResult<Option<rustler::Term<'a>>> {
+    score
+        .map(|s| Ok(score_type.encode(env, make_u64(s)?)))
+        .transpose()
+}
+
+fn make_u64(bytes: &[u8]) -> Result<u64, StoreError> {
+    decode_u64(bytes)
+        .ok_or_else(|| StoreError::Corruption(format!("invalid score of {} bytes", bytes.len())))
+}
+
+fn decode_u64(bytes: &[u8]) -> Option<u64> {
+    bytes.try_into().ok().map(u64::from_be_bytes)
+}
+
+fn index_member(index_key: &[u8], score_byte_len: usize) -> Result<&[u8], StoreError> {
+    index_key.get(score_byte_len..).ok_or_else(|| {
+        StoreError::Corruption(format!(
+            "invalid score index entry of {} bytes",
+            index_key.len()
+        ))
+    })
+}
+
+// The score stored for a member, if it has one.
+fn member_score(key_tree: &sled::Tree, key: &[u8]) -> Result<Option<u64>, StoreError> {
+    let score_key = key
+        .iter()
+        .chain(SCORE_SUFFIX.iter())
+        .copied()
+        .collect::<Vec<_>>();
+    key_tree
+        .get(score_key)?
+        .map(|score| make_u64(&score))
+        .transpose()
+}
 
-    bin.release(env)
+// The number of members in a collection, as kept up to date by `adjust_card`.
+fn member_count(key_tree: &sled::Tree) -> Result<u64, StoreError> {
+    match key_tree.get(CARD_KEY)? {
+        Some(card) => make_u64(&card),
+        None => Ok(0),
+    }
 }
 
-fn make_u64<'a>(bytes: &[u8]) -> u64 {
-    let b = bytes.try_into().expect("Invalid number of bytes");
-    u64::from_be_bytes(b)
+// The score entries of the members starting with `prefix`, with their decoded scores.
+fn prefix_scores<'t>(
+    key_tree: &'t sled::Tree,
+    prefix: &[u8],
+) -> impl DoubleEndedIterator<Item = Result<(IVec, u64), StoreError>> + 't {
+    key_tree
+        .scan_prefix(prefix)
+        .filter_map(|result| match result {
+            Ok((k, v)) if k.last() == SCORE_SUFFIX.last() => {
+                Some(make_u64(&v).map(|score| (k, score)))
+            }
+            Ok(_) => None,
+            Err(e) => Some(Err(e.into())),
+        })
 }
 
+#[allow(non_local_definitions)]
 fn load(env: rustler::Env, _info: rustler::Term) -> bool {
     rustler::resource!(DbResource, env);
     true
@@ -766,19 +3612,195 @@ rustler::init!(
     [
         open,
         clear,
+        flush,
+        flush_async,
+        close,
         zgetbykey,
+        zmget,
+        zmscore,
         zrangebyscore,
         zrangebyprefixscore,
+        zrevrangebyscore,
+        zrevrangebyprefixscore,
+        zscanbyscore,
+        zrevscanbyscore,
+        zscanbyprefixscore,
+        zrevscanbyprefixscore,
         zadd,
+        zmadd,
         zrem,
+        zpopkey,
+        zpopmin,
+        zpopmax,
         zscore,
         zscoreupdate,
+        zincrby,
         zrembyrangebyscore,
+        zscoretype,
+        zsetscoretype,
+        zcard,
+        zcount,
         zexists,
         lpush,
         rpush,
         rpop,
-        lpop
+        lpop,
+        pipeline,
+        transaction,
+        llen,
+        lrange,
+        lindex,
+        lpeek,
+        rpeek,
+        ltrim,
+        lrem,
+        blpop,
+        brpop,
+        bzpopmin,
+        cancel_blocking
     ],
     load = load
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_scores_are_errors() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let key_tree = db.open_tree(b"keysmycollection").unwrap();
+        key_tree.insert(b"hellos", &[1, 2, 3]).unwrap();
+        key_tree.insert(b"worlds", &42u64.to_be_bytes()).unwrap();
+
+        assert!(matches!(
+            member_score(&key_tree, b"hello"),
+            Err(StoreError::Corruption(_))
+        ));
+        assert_eq!(member_score(&key_tree, b"world").unwrap(), Some(42));
+        assert_eq!(member_score(&key_tree, b"missing").unwrap(), None);
+
+        let mut scores = prefix_scores(&key_tree, b"");
+        assert!(matches!(
+            scores.next(),
+            Some(Err(StoreError::Corruption(_)))
+        ));
+        assert!(matches!(scores.next(), Some(Ok((_, 42)))));
+        assert!(scores.next().is_none());
+
+        let score_tree = db.open_tree(b"scoresmycollection").unwrap();
+        score_tree.insert([0, 0, 1], b"").unwrap();
+        let index_key = score_tree.iter().keys().next().unwrap().unwrap();
+        assert!(matches!(
+            index_member(&index_key, 8),
+            Err(StoreError::Corruption(_))
+        ));
+    }
+
+    #[test]
+    fn list_spans_resolve_negative_indices() {
+        assert_eq!(list_span(5, 0, -1), Some((0, 5)));
+        assert_eq!(list_span(5, 1, 2), Some((1, 2)));
+        assert_eq!(list_span(5, -2, -1), Some((3, 2)));
+        assert_eq!(list_span(5, -10, 10), Some((0, 5)));
+        assert_eq!(list_span(5, 3, 1), None);
+        assert_eq!(list_span(5, 5, 10), None);
+        assert_eq!(list_span(5, -10, -6), None);
+        assert_eq!(list_span(0, 0, -1), None);
+    }
+
+    #[test]
+    fn score_sort_keys_keep_score_order() {
+        let ints = [i64::MIN, -5, -1, 0, 1, 5, i64::MAX];
+        let keys = ints.map(i64_sort_key);
+        assert!(keys
+            .windows(2)
+            .all(|w| w[0].to_be_bytes() < w[1].to_be_bytes()));
+        assert_eq!(keys.map(i64_from_sort_key), ints);
+
+        let floats = [
+            f64::NEG_INFINITY,
+            f64::MIN,
+            -2.5,
+            -f64::MIN_POSITIVE,
+            0.0,
+            f64::MIN_POSITIVE,
+            1.0,
+            2.5,
+            f64::MAX,
+            f64::INFINITY,
+        ];
+        let keys = floats.map(f64_sort_key);
+        assert!(keys
+            .windows(2)
+            .all(|w| w[0].to_be_bytes() < w[1].to_be_bytes()));
+        assert_eq!(keys.map(f64_from_sort_key), floats);
+        assert_eq!(f64_sort_key(-0.0), f64_sort_key(0.0));
+    }
+
+    #[test]
+    fn member_counts_are_backfilled() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let key_tree = db.open_tree(b"keysmycollection").unwrap();
+        key_tree.insert(b"as", &1u64.to_be_bytes()).unwrap();
+        key_tree.insert(b"av", b"value").unwrap();
+        key_tree.insert(b"ats", &2u64.to_be_bytes()).unwrap();
+        key_tree.insert(b"bv", b"value").unwrap();
+        db.open_tree(b"keysempty").unwrap();
+        let list_tree = db.open_tree(b"listsmylist").unwrap();
+        list_tree.insert(1i64.to_be_bytes(), b"one").unwrap();
+        list_tree.insert(2i64.to_be_bytes(), b"two").unwrap();
+
+        backfill_counts(&db).unwrap();
+        assert_eq!(member_count(&key_tree).unwrap(), 3);
+        assert!(db.open_tree(b"keysempty").unwrap().is_empty());
+        assert_eq!(
+            list_tree.get(CARD_KEY).unwrap(),
+            Some(IVec::from(&2u64.to_be_bytes()))
+        );
+
+        key_tree.insert(CARD_KEY, &7u64.to_be_bytes()).unwrap();
+        backfill_counts(&db).unwrap();
+        assert_eq!(member_count(&key_tree).unwrap(), 7);
+
+        key_tree.insert(CARD_KEY, &[7]).unwrap();
+        assert!(matches!(
+            member_count(&key_tree),
+            Err(StoreError::Corruption(_))
+        ));
+    }
+
+    #[test]
+    fn score_bounds_select_index_keys() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let score_tree = db.open_tree(b"scoresmycollection").unwrap();
+        for (score, key) in [(1u64, "a"), (2, "b"), (2, "c"), (3, "d"), (u64::MAX, "e")] {
+            let mut index_key = score.to_be_bytes().to_vec();
+            index_key.extend_from_slice(key.as_bytes());
+            score_tree.insert(index_key, b"").unwrap();
+        }
+        let members = |min, max| match (ScoreRange { min, max }).index_bounds() {
+            Some(bounds) => score_tree
+                .range(bounds)
+                .keys()
+                .map(|k| k.unwrap()[8..].to_vec())
+                .collect::<Vec<_>>(),
+            None => vec![],
+        };
+
+        assert_eq!(
+            members(Bound::Included(2), Bound::Included(2)),
+            vec![b"b".to_vec(), b"c".to_vec()]
+        );
+        assert_eq!(
+            members(Bound::Excluded(1), Bound::Excluded(3)),
+            vec![b"b".to_vec(), b"c".to_vec()]
+        );
+        assert_eq!(
+            members(Bound::Excluded(2), Bound::Included(u64::MAX)),
+            vec![b"d".to_vec(), b"e".to_vec()]
+        );
+        assert!(members(Bound::Excluded(u64::MAX), Bound::Unbounded).is_empty());
+        assert!(members(Bound::Unbounded, Bound::Excluded(0)).is_empty());
+    }
+}
@@ -78,4 +78,694 @@ defmodule SortedSetKvTest do
     assert "0" == SortedSetKV.lpop(db, "mylist")
     assert "2" == SortedSetKV.rpop(db, "mylist")
   end
+
+  test "opens with options" do
+    {:ok, db} = SortedSetKV.open("testdb_tmp1", temporary: true, mode: :low_space)
+    :ok = SortedSetKV.zadd(db, "mycollection", "hello", "world", 42, true)
+    assert {"world", 42} == SortedSetKV.zgetbykey(db, "mycollection", "hello", 0)
+
+    {:ok, db} = SortedSetKV.open("testdb_tmp2", %{temporary: true, cache_capacity: 1_000_000})
+    assert nil == SortedSetKV.zgetbykey(db, "mycollection", "hello", 0)
+
+    assert_raise ArgumentError, fn -> SortedSetKV.open("testdb_tmp3", unknown: true) end
+  end
+
+  test "flushes and closes" do
+    {:ok, db} = SortedSetKV.open("testdb_close", temporary: true)
+    :ok = SortedSetKV.zadd(db, "mycollection", "hello", "world", 42, true)
+    assert is_integer(SortedSetKV.flush(db))
+
+    {:ok, ref} = SortedSetKV.flush_async(db)
+    assert_receive {:sortedsetkv, ^ref, {:ok, _}}, 5_000
+
+    refs = for _ <- 1..20, do: elem(SortedSetKV.flush_async(db), 1)
+    for ref <- refs, do: assert_receive({:sortedsetkv, ^ref, {:ok, _}}, 5_000)
+
+    :ok = SortedSetKV.close(db)
+    assert {:error, :closed} == SortedSetKV.close(db)
+    assert {:error, :closed} == SortedSetKV.zscore(db, "mycollection", "hello")
+    assert {:error, :closed} == SortedSetKV.lpop(db, "mylist")
+    assert {:error, :closed} == SortedSetKV.flush_async(db)
+  end
+
+  test "returns structured errors" do
+    assert {:error, {:unsupported, message}} =
+             SortedSetKV.open("testdb_err", temporary: true, compression_factor: 50)
+
+    assert is_binary(message)
+  end
+
+  test "reopens collections after they are dropped" do
+    {:ok, db} = SortedSetKV.open("testdb_reopen", temporary: true)
+    :ok = SortedSetKV.zadd(db, "mycollection", "hello", "world", 42, true)
+    :ok = SortedSetKV.zrem(db, "mycollection", "hello")
+    :ok = SortedSetKV.zadd(db, "mycollection", "hello", "again", 43, true)
+    assert {"again", 43} == SortedSetKV.zgetbykey(db, "mycollection", "hello", 0)
+    assert ["hello"] == SortedSetKV.zrangebyscore(db, "mycollection", 0, nil, 0, 10)
+
+    :ok = SortedSetKV.clear(db)
+    assert nil == SortedSetKV.zgetbykey(db, "mycollection", "hello", 0)
+    :ok = SortedSetKV.zadd(db, "mycollection", "hello", "world", 42, true)
+    assert {"world", 42} == SortedSetKV.zgetbykey(db, "mycollection", "hello", 0)
+  end
+
+  test "reads on missing collections return empty results" do
+    {:ok, db} = SortedSetKV.open("testdb_missing", temporary: true)
+    assert {false, nil} == SortedSetKV.zscore(db, "missing", "hello")
+    assert nil == SortedSetKV.zgetbykey(db, "missing", "hello", 0)
+    assert [] == SortedSetKV.zrangebyscore(db, "missing", 0, nil, 0, 10)
+    assert [] == SortedSetKV.zrangebyprefixscore(db, "missing", "he", 0, nil, 0, 10)
+    assert false == SortedSetKV.zexists(db, "missing", 0, nil)
+    assert nil == SortedSetKV.lpop(db, "missing")
+    assert nil == SortedSetKV.rpop(db, "missing")
+  end
+
+  test "concurrent removals keep the score index and keys consistent" do
+    {:ok, db} = SortedSetKV.open("testdb_consistency", temporary: true)
+    keys = for i <- 1..1_000, do: "key#{i}"
+
+    for {key, i} <- Enum.with_index(keys) do
+      :ok = SortedSetKV.zadd(db, "mycollection", key, "value", i, false)
+    end
+
+    remover =
+      Task.async(fn -> SortedSetKV.zrembyrangebyscore(db, "mycollection", 0, 500, 1_000) end)
+
+    deleter =
+      Task.async(fn ->
+        for {key, i} <- Enum.with_index(keys), rem(i, 5) == 0 do
+          SortedSetKV.zrem(db, "mycollection", key)
+        end
+      end)
+
+    for {key, i} <- Enum.with_index(keys), rem(i, 3) == 0 do
+      :ok = SortedSetKV.zadd(db, "mycollection", key, "value", i + 1_000, false)
+    end
+
+    assert is_integer(Task.await(remover))
+    Task.await(deleter)
+    indexed = SortedSetKV.zrangebyscore(db, "mycollection", 0, nil, 0, 10_000)
+
+    for key <- keys do
+      case SortedSetKV.zscore(db, "mycollection", key) do
+        {true, score} ->
+          assert key in indexed
+          assert [key] == SortedSetKV.zrangebyscore(db, "mycollection", score, score + 1, 0, 10)
+
+        {false, nil} ->
+          refute key in indexed
+          assert nil == SortedSetKV.zgetbykey(db, "mycollection", key, 0)
+      end
+    end
+  end
+
+  test "concurrent pushes and pops do not lose elements" do
+    {:ok, db} = SortedSetKV.open("testdb_stress", temporary: true)
+    popped = :counters.new(1, [])
+    total = 8 * 500
+
+    pushers =
+      for p <- 1..8 do
+        Task.async(fn ->
+          for i <- 1..500, do: :ok = SortedSetKV.rpush(db, "mylist", "#{p}-#{i}")
+        end)
+      end
+
+    poppers = for _ <- 1..8, do: Task.async(fn -> pop_until(db, popped, total, []) end)
+
+    Task.await_many(pushers, 30_000)
+    values = poppers |> Task.await_many(30_000) |> List.flatten()
+
+    assert length(values) == total
+    assert MapSet.size(MapSet.new(values)) == total
+    assert nil == SortedSetKV.lpop(db, "mylist")
+  end
+
+  test "concurrent adds and removes do not lose members" do
+    {:ok, db} = SortedSetKV.open("testdb_zstress", temporary: true)
+
+    tasks =
+      for p <- 1..8 do
+        Task.async(fn ->
+          for i <- 1..200 do
+            :ok = SortedSetKV.zadd(db, "myset", "temp-#{p}-#{i}", "v", i, false)
+            :ok = SortedSetKV.zrem(db, "myset", "temp-#{p}-#{i}")
+            :ok = SortedSetKV.zadd(db, "myset", "keep-#{p}-#{i}", "v", i, false)
+            :ok = SortedSetKV.zrem(db, "myset", "keep-#{p}-#{i}")
+            :ok = SortedSetKV.zadd(db, "myset", "keep-#{p}-#{i}", "v", i, false)
+          end
+        end)
+      end
+
+    Task.await_many(tasks, 30_000)
+
+    for p <- 1..8, i <- 1..200 do
+      assert {true, ^i} = SortedSetKV.zscore(db, "myset", "keep-#{p}-#{i}")
+      assert {false, nil} == SortedSetKV.zscore(db, "myset", "temp-#{p}-#{i}")
+    end
+  end
+
+  test "returns previous entries from zadd and zpopkey" do
+    {:ok, db} = SortedSetKV.open("testdb_previous", temporary: true)
+
+    assert {true, nil} ==
+             SortedSetKV.zadd(db, "mycollection", "hello", "world", 42, return_previous: true)
+
+    assert {false, {"world", 42}} ==
+             SortedSetKV.zadd(db, "mycollection", "hello", "other", 10,
+               gt: true,
+               return_previous: true
+             )
+
+    assert {true, {"world", 42}} ==
+             SortedSetKV.zadd(db, "mycollection", "hello", "other", 50, %{
+               gt: true,
+               return_previous: true
+             })
+
+    :ok = SortedSetKV.zadd(db, "mycollection", "novalue", nil, 7, [])
+
+    assert {"other", 50} == SortedSetKV.zpopkey(db, "mycollection", "hello")
+    assert nil == SortedSetKV.zpopkey(db, "mycollection", "hello")
+    assert {nil, 7} == SortedSetKV.zpopkey(db, "mycollection", "novalue")
+    assert [] == SortedSetKV.zrangebyscore(db, "mycollection", 0, nil, 0, 10)
+  end
+
+  test "conditional zadd and zscoreupdate modes" do
+    {:ok, db} = SortedSetKV.open("testdb_modes", temporary: true)
+
+    assert 1 == SortedSetKV.zadd(db, "c", "a", "v1", 10, nx: true, ch: true)
+    assert 0 == SortedSetKV.zadd(db, "c", "a", "v2", 20, nx: true, ch: true)
+    assert {"v1", 10} == SortedSetKV.zgetbykey(db, "c", "a", 0)
+
+    assert 0 == SortedSetKV.zadd(db, "c", "b", "v1", 10, xx: true, ch: true)
+    assert {false, nil} == SortedSetKV.zscore(db, "c", "b")
+
+    assert 0 == SortedSetKV.zadd(db, "c", "a", "v1", 20, xx: true, lt: true, ch: true)
+    assert 1 == SortedSetKV.zadd(db, "c", "a", "v1", 5, xx: true, lt: true, ch: true)
+    assert {"v1", 5} == SortedSetKV.zgetbykey(db, "c", "a", 0)
+
+    # gt and lt never remove an existing score
+    assert 0 == SortedSetKV.zadd(db, "c", "a", "v1", nil, gt: true, ch: true)
+    assert {true, 5} == SortedSetKV.zscore(db, "c", "a")
+
+    # Writing the same entry again is not a change
+    assert 0 == SortedSetKV.zadd(db, "c", "a", "v1", 5, ch: true)
+    assert ["a"] == SortedSetKV.zrangebyscore(db, "c", 5, 6, 0, 10)
+
+    assert 0 == SortedSetKV.zscoreupdate(db, "c", "a", 6, lt: true, ch: true)
+    assert 1 == SortedSetKV.zscoreupdate(db, "c", "a", 4, lt: true, ch: true)
+    assert 0 == SortedSetKV.zscoreupdate(db, "c", "missing", 4, ch: true)
+    assert {false, nil} == SortedSetKV.zscore(db, "c", "missing")
+
+    # Only keys that already have a score are rescored
+    :ok = SortedSetKV.zadd(db, "c", "novalue", "v", nil, [])
+    assert 0 == SortedSetKV.zscoreupdate(db, "c", "novalue", 3, gt: true, ch: true)
+    assert {true, nil} == SortedSetKV.zscore(db, "c", "novalue")
+
+    # The gt boolean still removes the score when given nil
+    :ok = SortedSetKV.zscoreupdate(db, "c", "a", nil, true)
+    assert {true, nil} == SortedSetKV.zscore(db, "c", "a")
+    assert [] == SortedSetKV.zrangebyscore(db, "c", 0, nil, 0, 10)
+
+    assert_raise ArgumentError, fn ->
+      SortedSetKV.zadd(db, "c", "a", "v", 1, nx: true, xx: true)
+    end
+
+    assert_raise ArgumentError, fn ->
+      SortedSetKV.zadd(db, "c", "a", "v", 1, gt: true, lt: true)
+    end
+  end
+
+  test "zincrby adjusts scores atomically" do
+    {:ok, db} = SortedSetKV.open("testdb_incr", temporary: true)
+
+    assert 5 == SortedSetKV.zincrby(db, "c", "hits", 5)
+    assert 3 == SortedSetKV.zincrby(db, "c", "hits", -2)
+    assert {:error, :overflow} == SortedSetKV.zincrby(db, "c", "hits", -10)
+    assert {true, 3} == SortedSetKV.zscore(db, "c", "hits")
+    assert ["hits"] == SortedSetKV.zrangebyscore(db, "c", 3, 4, 0, 10)
+
+    :ok = SortedSetKV.zadd(db, "c", "valued", "v", nil, false)
+    assert 7 == SortedSetKV.zincrby(db, "c", "valued", 7)
+    assert {"v", 7} == SortedSetKV.zgetbykey(db, "c", "valued", 0)
+
+    tasks =
+      for _ <- 1..8 do
+        Task.async(fn ->
+          for _ <- 1..100, do: SortedSetKV.zincrby(db, "c", "race", 1)
+        end)
+      end
+
+    Task.await_many(tasks, 30_000)
+    assert {true, 800} == SortedSetKV.zscore(db, "c", "race")
+    assert ["race"] == SortedSetKV.zrangebyscore(db, "c", 800, 801, 0, 10)
+  end
+
+  test "descending range queries" do
+    {:ok, db} = SortedSetKV.open("testdb_rev", temporary: true)
+
+    for {key, score} <- [{"a", 1}, {"b", 2}, {"c", 3}, {"d", 4}, {"ab", 5}] do
+      :ok = SortedSetKV.zadd(db, "c", key, "v", score, false)
+    end
+
+    assert ["ab", "d", "c", "b", "a"] == SortedSetKV.zrevrangebyscore(db, "c", 0, nil, 0, 10)
+    assert ["c", "b"] == SortedSetKV.zrevrangebyscore(db, "c", 0, 4, 0, 2)
+    assert ["b"] == SortedSetKV.zrevrangebyscore(db, "c", 2, 4, 1, 10)
+    assert [] == SortedSetKV.zrevrangebyscore(db, "missing", 0, nil, 0, 10)
+
+    ascending = SortedSetKV.zrangebyprefixscore(db, "c", "a", 0, nil, 0, 10)
+    assert 2 == length(ascending)
+    assert Enum.reverse(ascending) ==
+             SortedSetKV.zrevrangebyprefixscore(db, "c", "a", 0, nil, 0, 10)
+
+    assert [List.first(ascending)] ==
+             SortedSetKV.zrevrangebyprefixscore(db, "c", "a", 0, nil, 1, 10)
+  end
+
+  test "range queries return scores and values" do
+    {:ok, db} = SortedSetKV.open("testdb_withscores", temporary: true)
+
+    :ok = SortedSetKV.zadd(db, "c", "a", "va", 1, false)
+    :ok = SortedSetKV.zadd(db, "c", "b", nil, 2, false)
+    :ok = SortedSetKV.zadd(db, "c", "ab", "vab", 3, false)
+
+    assert [{"a", 1}, {"b", 2}] ==
+             SortedSetKV.zrangebyscore(db, "c", 0, 3, 0, 10, withscores: true)
+
+    assert [{"ab", "vab", 3}, {"b", nil, 2}] ==
+             SortedSetKV.zrevrangebyscore(db, "c", 2, nil, 0, 10, withvalues: true)
+
+    assert ["a"] == SortedSetKV.zrangebyscore(db, "c", 0, 2, 0, 10, [])
+    assert ["a"] == SortedSetKV.zrangebyscore(db, "c", 0, 2, 0, 10)
+
+    assert [{"ab", 3}, {"a", 1}] ==
+             SortedSetKV.zrangebyprefixscore(db, "c", "a", 0, nil, 0, 10, withscores: true)
+
+    assert [{"a", "va", 1}, {"ab", "vab", 3}] ==
+             SortedSetKV.zrevrangebyprefixscore(db, "c", "a", 0, nil, 0, 10, %{withvalues: true})
+
+    assert [] == SortedSetKV.zrangebyscore(db, "missing", 0, nil, 0, 10, withvalues: true)
+
+    assert_raise ArgumentError, fn ->
+      SortedSetKV.zrangebyscore(db, "c", 0, nil, 0, 10, bogus: true)
+    end
+  end
+
+  test "cursor pagination over score and prefix ranges" do
+    {:ok, db} = SortedSetKV.open("testdb_scan", temporary: true)
+
+    for i <- 1..5 do
+      :ok = SortedSetKV.zadd(db, "c", "k#{i}", "v#{i}", i, false)
+    end
+
+    assert {["k1", "k2"], cursor} = SortedSetKV.zscanbyscore(db, "c", 0, nil, nil, 2)
+    :ok = SortedSetKV.zadd(db, "c", "k0", "v0", 0, false)
+    assert {[{"k3", 3}, {"k4", 4}], cursor} =
+             SortedSetKV.zscanbyscore(db, "c", 0, nil, cursor, 2, withscores: true)
+
+    assert {["k5"], nil} == SortedSetKV.zscanbyscore(db, "c", 0, nil, cursor, 2)
+
+    assert {["k5", "k4"], cursor} = SortedSetKV.zrevscanbyscore(db, "c", 2, nil, nil, 2)
+    assert {["k3", "k2"], cursor} = SortedSetKV.zrevscanbyscore(db, "c", 2, nil, cursor, 2)
+    assert {[], nil} == SortedSetKV.zrevscanbyscore(db, "c", 2, nil, cursor, 2)
+
+    assert {["k0", "k1", "k2"], cursor} =
+             SortedSetKV.zscanbyprefixscore(db, "c", "k", 0, 5, nil, 3)
+
+    assert {[{"k3", "v3", 3}, {"k4", "v4", 4}], nil} ==
+             SortedSetKV.zscanbyprefixscore(db, "c", "k", 0, 5, cursor, 3, withvalues: true)
+
+    assert {["k5", "k4"], cursor} =
+             SortedSetKV.zrevscanbyprefixscore(db, "c", "k", 0, nil, nil, 2)
+
+    assert {["k3", "k2"], _} = SortedSetKV.zrevscanbyprefixscore(db, "c", "k", 0, nil, cursor, 2)
+
+    assert {[], nil} == SortedSetKV.zscanbyscore(db, "missing", 0, nil, nil, 2)
+
+    assert_raise ArgumentError, fn ->
+      SortedSetKV.zscanbyprefixscore(db, "c", "x", 0, nil, "k1s", 2)
+    end
+  end
+
+  test "inclusive, exclusive and infinite score bounds" do
+    {:ok, db} = SortedSetKV.open("testdb_bounds", temporary: true)
+
+    for {key, score} <- [{"a", 1}, {"b", 2}, {"c", 2}, {"d", 3}] do
+      :ok = SortedSetKV.zadd(db, "c", key, "v", score, false)
+    end
+
+    assert ["b", "c"] == SortedSetKV.zrangebyscore(db, "c", {:incl, 2}, {:incl, 2}, 0, 10)
+    assert ["d"] == SortedSetKV.zrangebyscore(db, "c", {:excl, 2}, :inf, 0, 10)
+    assert ["a", "b", "c"] == SortedSetKV.zrangebyscore(db, "c", :"-inf", 3, 0, 10)
+    assert [] == SortedSetKV.zrangebyscore(db, "c", :inf, nil, 0, 10)
+    assert ["d", "c", "b"] == SortedSetKV.zrevrangebyscore(db, "c", {:incl, 2}, {:incl, 3}, 0, 10)
+
+    assert ["b", "c"] ==
+             SortedSetKV.zrangebyprefixscore(db, "c", "", {:incl, 2}, {:excl, 3}, 0, 10)
+
+    assert {["a"], _} = SortedSetKV.zscanbyscore(db, "c", nil, {:excl, 2}, nil, 1)
+
+    assert SortedSetKV.zexists(db, "c", {:incl, 3}, {:incl, 3})
+    refute SortedSetKV.zexists(db, "c", {:excl, 3}, :inf)
+    assert 2 == SortedSetKV.zrembyrangebyscore(db, "c", {:incl, 2}, {:incl, 2}, 10)
+    assert ["a", "d"] == SortedSetKV.zrangebyscore(db, "c", :"-inf", :inf, 0, 10)
+
+    assert_raise ArgumentError, fn ->
+      SortedSetKV.zrangebyscore(db, "c", {:open, 1}, nil, 0, 10)
+    end
+  end
+
+  test "zcard and zcount" do
+    {:ok, db} = SortedSetKV.open("testdb_card", temporary: true)
+
+    assert 0 == SortedSetKV.zcard(db, "c")
+    for i <- 1..10, do: :ok = SortedSetKV.zadd(db, "c", "k#{i}", "v", i, false)
+    :ok = SortedSetKV.zadd(db, "c", "unscored", "v", nil, false)
+    :ok = SortedSetKV.zadd(db, "c", "k1", "v2", 1, false)
+    assert 11 == SortedSetKV.zcard(db, "c")
+
+    assert 10 == SortedSetKV.zcount(db, "c", :"-inf", :inf)
+    assert 3 == SortedSetKV.zcount(db, "c", {:incl, 2}, {:incl, 4})
+    assert 0 == SortedSetKV.zcount(db, "missing", 0, nil)
+
+    :ok = SortedSetKV.zrem(db, "c", "k1")
+    :ok = SortedSetKV.zrem(db, "c", "k1")
+    {"v", nil} = SortedSetKV.zpopkey(db, "c", "unscored")
+    assert 4 == SortedSetKV.zrembyrangebyscore(db, "c", 2, 6, 100)
+    assert 5 == SortedSetKV.zcard(db, "c")
+    assert 5 == SortedSetKV.zincrby(db, "c", "new", 5)
+    assert 6 == SortedSetKV.zcard(db, "c")
+
+    assert 6 == SortedSetKV.zrembyrangebyscore(db, "c", 0, nil, 100)
+    assert 0 == SortedSetKV.zcard(db, "c")
+  end
+
+  test "zpopmin and zpopmax" do
+    {:ok, db} = SortedSetKV.open("testdb_popmin", temporary: true)
+
+    for i <- 1..6, do: :ok = SortedSetKV.zadd(db, "c", "k#{i}", "v#{i}", i, false)
+    :ok = SortedSetKV.zadd(db, "c", "novalue", nil, 7, false)
+
+    assert [{"k1", "v1", 1}, {"k2", "v2", 2}] == SortedSetKV.zpopmin(db, "c", 2)
+    assert [{"k3", "v3", 3}] == SortedSetKV.zpopmin(db, "c", 5, 4)
+    assert [] == SortedSetKV.zpopmin(db, "c", 5, {:excl, 4})
+    assert [{"novalue", nil, 7}, {"k6", "v6", 6}] == SortedSetKV.zpopmax(db, "c", 2)
+    assert [{"k4", "v4", 4}] == SortedSetKV.zpopmax(db, "c", 1, {:excl, 5})
+    assert 1 == SortedSetKV.zcard(db, "c")
+    assert [] == SortedSetKV.zpopmin(db, "missing", 1)
+
+    for i <- 1..400, do: :ok = SortedSetKV.zadd(db, "q", "k#{i}", "v", i, false)
+    tasks = for _ <- 1..8, do: Task.async(fn -> pop_all(db, []) end)
+    popped = tasks |> Task.await_many(30_000) |> List.flatten() |> Enum.map(fn {k, _, _} -> k end)
+    assert 400 == length(popped)
+    assert 400 == length(Enum.uniq(popped))
+  end
+
+  defp pop_all(db, acc) do
+    case SortedSetKV.zpopmin(db, "q", 7) do
+      [] -> acc
+      popped -> pop_all(db, [popped | acc])
+    end
+  end
+
+  test "blocking pops" do
+    {:ok, db} = SortedSetKV.open("testdb_blocking", temporary: true)
+
+    :ok = SortedSetKV.rpush(db, "l", "ready")
+    {:ok, ref} = SortedSetKV.blpop(db, "l", 1_000)
+    assert_receive {:sortedsetkv, ^ref, "ready"}
+
+    {:ok, ref} = SortedSetKV.brpop(db, "l", 50)
+    assert_receive {:sortedsetkv, ^ref, :timeout}, 1_000
+
+    {:ok, ref} = SortedSetKV.blpop(db, "l", 5_000)
+    refute_receive {:sortedsetkv, ^ref, _}, 100
+    :ok = SortedSetKV.rpush(db, "l", "pushed")
+    assert_receive {:sortedsetkv, ^ref, "pushed"}, 1_000
+    assert nil == SortedSetKV.lpop(db, "l")
+
+    refs = for _ <- 1..3, do: elem(SortedSetKV.brpop(db, "l2", 5_000), 1)
+    for i <- 1..3, do: :ok = SortedSetKV.lpush(db, "l2", "v#{i}")
+    values =
+      for ref <- refs do
+        assert_receive {:sortedsetkv, ^ref, value}, 1_000
+        value
+      end
+
+    assert ["v1", "v2", "v3"] == Enum.sort(values)
+
+    {:ok, ref} = SortedSetKV.bzpopmin(db, "z", 5_000)
+    :ok = SortedSetKV.zadd(db, "z", "due", "payload", 10, false)
+    assert_receive {:sortedsetkv, ^ref, {"due", "payload", 10}}, 1_000
+
+    :ok = SortedSetKV.zadd(db, "z", "later", "payload", 100, false)
+    {:ok, ref} = SortedSetKV.bzpopmin(db, "z", 50, {:incl, 50})
+    assert_receive {:sortedsetkv, ^ref, :timeout}, 1_000
+    assert 1 == SortedSetKV.zcard(db, "z")
+
+    {:ok, ref} = SortedSetKV.blpop(db, "cancel", 5_000)
+    assert :cancelled == SortedSetKV.bcancel(db, ref)
+    :ok = SortedSetKV.rpush(db, "cancel", "kept")
+    refute_receive {:sortedsetkv, ^ref, _}, 300
+    assert "kept" == SortedSetKV.lpop(db, "cancel")
+
+    {:ok, ref} = SortedSetKV.blpop(db, "cancel", 5_000)
+    :ok = SortedSetKV.rpush(db, "cancel", "popped")
+    Process.sleep(300)
+    assert "popped" == SortedSetKV.bcancel(db, ref)
+    refute_receive {:sortedsetkv, ^ref, _}, 100
+
+    {pid, monitor} = spawn_monitor(fn -> SortedSetKV.blpop(db, "gone", 5_000) end)
+    assert_receive {:DOWN, ^monitor, :process, ^pid, _}
+    :ok = SortedSetKV.rpush(db, "gone", "kept")
+    Process.sleep(300)
+    assert "kept" == SortedSetKV.lpop(db, "gone")
+  end
+
+  test "list inspection, trimming and removal" do
+    {:ok, db} = SortedSetKV.open("testdb_lists", temporary: true)
+
+    assert 0 == SortedSetKV.llen(db, "l")
+    assert [] == SortedSetKV.lrange(db, "l", 0, -1)
+    assert nil == SortedSetKV.lpeek(db, "l")
+
+    for v <- ["c", "d", "e"], do: :ok = SortedSetKV.rpush(db, "l", v)
+    for v <- ["b", "a"], do: :ok = SortedSetKV.lpush(db, "l", v)
+    assert 5 == SortedSetKV.llen(db, "l")
+    assert ["a", "b", "c", "d", "e"] == SortedSetKV.lrange(db, "l", 0, -1)
+    assert ["d", "e"] == SortedSetKV.lrange(db, "l", -2, 10)
+    assert ["b", "c"] == SortedSetKV.lrange(db, "l", 1, 2)
+    assert [] == SortedSetKV.lrange(db, "l", 3, 1)
+    assert "c" == SortedSetKV.lindex(db, "l", 2)
+    assert "e" == SortedSetKV.lindex(db, "l", -1)
+    assert nil == SortedSetKV.lindex(db, "l", 5)
+    assert "a" == SortedSetKV.lpeek(db, "l")
+    assert "e" == SortedSetKV.rpeek(db, "l")
+
+    :ok = SortedSetKV.ltrim(db, "l", 1, -2)
+    assert ["b", "c", "d"] == SortedSetKV.lrange(db, "l", 0, -1)
+    assert "b" == SortedSetKV.lpop(db, "l")
+    assert 2 == SortedSetKV.llen(db, "l")
+
+    for v <- ["x", "c", "x"], do: :ok = SortedSetKV.rpush(db, "l", v)
+    assert 1 == SortedSetKV.lrem(db, "l", "x", -1)
+    assert ["c", "d", "x", "c"] == SortedSetKV.lrange(db, "l", 0, -1)
+    assert 2 == SortedSetKV.lrem(db, "l", "c", 0)
+    assert 0 == SortedSetKV.lrem(db, "l", "missing", 0)
+    assert ["d", "x"] == SortedSetKV.lrange(db, "l", 0, -1)
+    assert 2 == SortedSetKV.llen(db, "l")
+
+    :ok = SortedSetKV.ltrim(db, "l", 5, 10)
+    assert 0 == SortedSetKV.llen(db, "l")
+    assert nil == SortedSetKV.rpop(db, "l")
+  end
+
+  test "pipelines run batches of operations" do
+    {:ok, db} = SortedSetKV.open("testdb_pipeline", temporary: true)
+
+    assert [:ok, 5, {true, 5}, {"v", 5}, nil, :ok, {true, nil}] ==
+             SortedSetKV.pipeline(db, [
+               {:zadd, "p", "a", "v", 3, true},
+               {:zincrby, "p", "a", 2},
+               {:zscore, "p", "a"},
+               {:zgetbykey, "p", "a", 0},
+               {:zgetbykey, "missing", "a", 0},
+               {:rpush, "l", "x"},
+               {:zadd, "p", "b", nil, 1, [return_previous: true]}
+             ])
+
+    assert ["x"] == SortedSetKV.lrange(db, "l", 0, -1)
+    assert 2 == SortedSetKV.zcard(db, "p")
+
+    assert [:ok, {:error, _}] =
+             SortedSetKV.pipeline(db, [{:zrem, "p", "b"}, {:zincrby, "p", "a", -10}])
+
+    assert {false, nil} == SortedSetKV.zscore(db, "p", "b")
+    assert {true, 5} == SortedSetKV.zscore(db, "p", "a")
+
+    assert {:error, _} =
+             SortedSetKV.pipeline(
+               db,
+               [{:zrem, "p", "a"}, {:lpush, "l", "y"}, {:zincrby, "p", "c", -1}],
+               atomic: true
+             )
+
+    assert {true, 5} == SortedSetKV.zscore(db, "p", "a")
+    assert 1 == SortedSetKV.llen(db, "l")
+
+    assert [:ok, :ok] ==
+             SortedSetKV.pipeline(db, [{:zrem, "p", "a"}, {:lpush, "l", "y"}], atomic: true)
+
+    assert 0 == SortedSetKV.zcard(db, "p")
+    assert ["y", "x"] == SortedSetKV.lrange(db, "l", 0, -1)
+    assert_raise ArgumentError, fn -> SortedSetKV.pipeline(db, [{:unknown, "p"}]) end
+  end
+
+  test "transactions move elements between lists and sorted sets" do
+    {:ok, db} = SortedSetKV.open("testdb_transaction", temporary: true)
+
+    for v <- ["j1", "j2"], do: :ok = SortedSetKV.rpush(db, "jobs", v)
+
+    assert ["j1", :ok, {true, 100}] ==
+             SortedSetKV.transaction(db, [
+               {:lpop, "jobs"},
+               {:zadd, "inflight", {:popped, 0}, nil, 100},
+               {:zscore, "inflight", "j1"}
+             ])
+
+    assert [:ok, "j3", "j2", nil] ==
+             SortedSetKV.transaction(db, [
+               {:lpush, "jobs", "j3"},
+               {:lpop, "jobs"},
+               {:lpop, "jobs"},
+               {:rpop, "jobs"}
+             ])
+
+    assert [nil, nil] ==
+             SortedSetKV.transaction(db, [{:rpop, "jobs"}, {:rpush, "done", {:popped, 0}}])
+
+    :ok = SortedSetKV.rpush(db, "jobs", "j4")
+
+    assert {:error, _} =
+             SortedSetKV.transaction(db, [{:rpop, "jobs"}, {:zincrby, "inflight", "j1", -200}])
+
+    assert ["j4"] == SortedSetKV.lrange(db, "jobs", 0, -1)
+    assert 0 == SortedSetKV.llen(db, "done")
+
+    assert ["j4", :ok] ==
+             SortedSetKV.pipeline(db, [{:rpop, "jobs"}, {:rpush, "done", {:popped, 0}}])
+
+    assert ["j4"] == SortedSetKV.lrange(db, "done", 0, -1)
+
+    assert_raise ArgumentError, fn ->
+      SortedSetKV.transaction(db, [{:zrem, "x", {:popped, 0}}])
+    end
+  end
+
+  test "zmget and zmscore read many keys" do
+    {:ok, db} = SortedSetKV.open("testdb_zmget", temporary: true)
+
+    assert [nil, nil] == SortedSetKV.zmget(db, "m", ["a", "b"], 0)
+    assert [{false, nil}] == SortedSetKV.zmscore(db, "m", ["a"])
+
+    :ok = SortedSetKV.zadd(db, "m", "a", "va", 5, false)
+    :ok = SortedSetKV.zadd(db, "m", "b", nil, 1, false)
+    :ok = SortedSetKV.zadd(db, "m", "c", "", nil, false)
+
+    assert [{"va", 5}, {nil, 1}, {"", nil}, nil] ==
+             SortedSetKV.zmget(db, "m", ["a", "b", "c", "d"], 0)
+
+    assert [{"va", 5}, nil, {"", nil}] == SortedSetKV.zmget(db, "m", ["a", "b", "c"], 2)
+
+    assert [{true, 1}, {true, nil}, {false, nil}, {true, 5}] ==
+             SortedSetKV.zmscore(db, "m", ["b", "c", "d", "a"])
+
+    assert [] == SortedSetKV.zmget(db, "m", [], 0)
+  end
+
+  test "zmadd adds entries in bulk" do
+    {:ok, db} = SortedSetKV.open("testdb_zmadd", temporary: true)
+
+    :ok = SortedSetKV.zadd(db, "b", "a", "old", 5, false)
+
+    assert {2, 1, 0} ==
+             SortedSetKV.zmadd(db, "b", [{"a", "new", 7}, {"b", nil, 1}, {"c", "v", nil}])
+
+    assert {"new", 7} == SortedSetKV.zgetbykey(db, "b", "a", 0)
+    assert 3 == SortedSetKV.zcard(db, "b")
+
+    assert {1, 1, 2} ==
+             SortedSetKV.zmadd(
+               db,
+               "b",
+               [{"a", "new", 6}, {"b", nil, 2}, {"d", nil, 4}, {"c", "v", nil}],
+               gt: true,
+               batch_size: 1
+             )
+
+    assert {"new", 7} == SortedSetKV.zgetbykey(db, "b", "a", 0)
+    assert {true, 2} == SortedSetKV.zscore(db, "b", "b")
+    assert {0, 0, 1} == SortedSetKV.zmadd(db, "b", [{"e", nil, 1}], xx: true)
+    assert {0, 0, 0} == SortedSetKV.zmadd(db, "b", [], batch_size: 10)
+    assert 3 == SortedSetKV.zcount(db, "b", nil, nil)
+    assert_raise ArgumentError, fn -> SortedSetKV.zmadd(db, "b", [], batch_size: 0) end
+    assert_raise ArgumentError, fn -> SortedSetKV.zmadd(db, "b", [], ch: true) end
+  end
+
+  test "signed and float score types keep range order" do
+    {:ok, db} = SortedSetKV.open("testdb_score_types", temporary: true)
+
+    assert :u64 == SortedSetKV.zscoretype(db, "i")
+    assert :ok == SortedSetKV.zsetscoretype(db, "i", :i64)
+    assert :i64 == SortedSetKV.zscoretype(db, "i")
+
+    :ok = SortedSetKV.zadd(db, "i", "low", nil, -20, false)
+    :ok = SortedSetKV.zadd(db, "i", "mid", nil, 0, false)
+    :ok = SortedSetKV.zadd(db, "i", "high", nil, 15, false)
+
+    assert [{"low", -20}, {"mid", 0}] ==
+             SortedSetKV.zrangebyscore(db, "i", -100, {:incl, 0}, 0, 10, withscores: true)
+
+    assert 2 == SortedSetKV.zcount(db, "i", -20, {:incl, 0})
+    assert 1 == SortedSetKV.zcount(db, "i", -20, 0)
+    assert -5 == SortedSetKV.zincrby(db, "i", "high", -20)
+    assert {:error, :not_empty} == SortedSetKV.zsetscoretype(db, "i", :f64)
+
+    :ok = SortedSetKV.zsetscoretype(db, "f", :f64)
+    :ok = SortedSetKV.zadd(db, "f", "a", nil, -1.5, false)
+    :ok = SortedSetKV.zadd(db, "f", "b", nil, 2, false)
+    :ok = SortedSetKV.zadd(db, "f", "c", nil, 0.25, false)
+
+    assert [{"a", -1.5}, {"c", 0.25}, {"b", 2.0}] ==
+             SortedSetKV.zrangebyscore(db, "f", nil, nil, 0, 10, withscores: true)
+
+    assert ["c"] == SortedSetKV.zrangebyscore(db, "f", -1.0, 1, 0, 10)
+    assert 0.75 == SortedSetKV.zincrby(db, "f", "c", 0.5)
+    :ok = SortedSetKV.zadd(db, "f", "huge", nil, 1.0e308, false)
+    assert {:error, :overflow} == SortedSetKV.zincrby(db, "f", "huge", 1.0e308)
+    assert {true, 1.0e308} == SortedSetKV.zscore(db, "f", "huge")
+    assert_raise ArgumentError, fn -> SortedSetKV.zsetscoretype(db, "f", :i32) end
+
+    {:ok, ref} = SortedSetKV.bzpopmin(db, "w", 5_000)
+    assert {:error, :busy} == SortedSetKV.zsetscoretype(db, "w", :i64)
+    assert :cancelled == SortedSetKV.bcancel(db, ref)
+    Process.sleep(300)
+    assert :ok == SortedSetKV.zsetscoretype(db, "w", :i64)
+  end
+
+  defp pop_until(db, counter, total, acc) do
+    if :counters.get(counter, 1) >= total do
+      acc
+    else
+      case SortedSetKV.lpop(db, "mylist") do
+        nil ->
+          pop_until(db, counter, total, acc)
+
+        value ->
+          :counters.add(counter, 1, 1)
+          pop_until(db, counter, total, [value | acc])
+      end
+    end
+  end
 end